@@ -0,0 +1,94 @@
+//! Measures cache lookups per second as the number of threads grows, with a single shard and
+//! with the default number of shards. Run with `cargo bench --bench cache`.
+
+use hermes_dns::cache::Cache;
+use hermes_dns::dns::{Message, RCode, RData, RecordType, ResourceRecord};
+use hermes_dns::settings::CacheSettings;
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of distinct names queried.
+const NAMES: usize = 10_000;
+
+/// How long each combination of shards and threads is measured for.
+const RUN_TIME: Duration = Duration::from_secs(1);
+
+/// One in this many operations stores a response instead of looking one up.
+const INSERT_EVERY: usize = 16;
+
+const THREADS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+fn main() {
+    let queries: Vec<(Message, Message)> = (0..NAMES)
+        .map(|i| {
+            let name = format!("host{}.example.com", i);
+            let request = Message::new_simple_query(&name, RecordType::A, 0).unwrap();
+            let address = Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8);
+            let record = ResourceRecord::new(&name, 3600, RData::A(address)).unwrap();
+            let response =
+                Message::new_response(&request, RCode::NOERROR, vec![record], Vec::new());
+            (request, response)
+        })
+        .collect();
+
+    println!("{:>8} {:>8} {:>14}", "shards", "threads", "queries/s");
+    for shards in [1, 0] {
+        for threads in THREADS {
+            let cache = Cache::new(&CacheSettings {
+                enabled: true,
+                size: NAMES * 2,
+                shards,
+                max_stale: 0,
+                stale_ttl: 30,
+                prefetch_min_hits: 3,
+                prefetch_percent: 10,
+            });
+            for (request, response) in &queries {
+                cache.insert(request, response);
+            }
+
+            let operations = run(&cache, &queries, threads);
+            let label = if shards == 0 { "auto" } else { "1" };
+            println!(
+                "{:>8} {:>8} {:>14.0}",
+                label,
+                threads,
+                operations as f64 / RUN_TIME.as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Looks up names from every thread until the run time is over.
+///
+/// # Returns
+///
+/// The number of operations completed by all threads together.
+fn run(cache: &Cache, queries: &[(Message, Message)], threads: usize) -> usize {
+    let deadline = Instant::now() + RUN_TIME;
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                scope.spawn(move || {
+                    // Every thread walks the names in its own order, with a stride coprime to NAMES.
+                    let mut i = t * 7919;
+                    let mut operations = 0;
+                    while operations % 1024 != 0 || Instant::now() < deadline {
+                        let (request, response) = &queries[i % NAMES];
+                        if operations % INSERT_EVERY == 0 {
+                            cache.insert(request, response);
+                        } else {
+                            black_box(cache.query(request));
+                        }
+                        i += 7;
+                        operations += 1;
+                    }
+                    operations
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).sum()
+    })
+}
@@ -0,0 +1,17 @@
+#![no_main]
+
+use hermes_dns::dns::Message;
+use libfuzzer_sys::fuzz_target;
+
+// Feeds arbitrary bytes to the decoder. Anything it accepts must encode without panicking and
+// decode back to the same message.
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = Message::deserialize(data) else {
+        return;
+    };
+
+    let encoded = message.serialize();
+    let decoded = Message::deserialize(&encoded)
+        .unwrap_or_else(|e| panic!("failed to re-parse serialized message: {}", e));
+    assert_eq!(message, decoded);
+});
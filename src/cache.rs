@@ -1,19 +1,591 @@
-use crate::{dns::Message as DNSMessage, settings::CacheSettings};
+use crate::dns::{Message as DNSMessage, RCode, RecordType};
+use crate::settings::CacheSettings;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+/// A cache of upstream responses, split into shards by key so that concurrent requests rarely
+/// wait for the same lock. Each shard evicts with CLOCK, an approximation of LRU.
+///
+/// Negative responses are cached as well, RFC 2308: NODATA for the type that was asked for and
+/// NXDOMAIN for every type of the name, unless it came at the end of a CNAME chain. Expired
+/// responses are kept for `max_stale` seconds to answer with while the upstreams are unreachable,
+/// RFC 8767, and popular responses are refreshed shortly before they expire.
 pub struct Cache {
-    enabled: bool,
-    size: usize,
+    /// Empty if the cache is disabled.
+    shards: Vec<Mutex<Shard>>,
+    /// Picks the shard of a key.
+    hasher: RandomState,
+    /// How long after expiring a response may still be served while the upstreams fail.
+    max_stale: Duration,
+    /// The TTL of stale responses in seconds, and how often the upstreams are asked to refresh them.
+    stale_ttl: u32,
+    /// The number of hits after which a response is refreshed before it expires.
+    prefetch_min_hits: u32,
+    /// The share of its TTL, in percent, that a popular response has left when it is refreshed.
+    prefetch_percent: u32,
+}
+
+/// A response found in the cache.
+pub enum Cached {
+    /// A response that has not expired yet. `refresh` is `true` if it is popular and about to
+    /// expire, so the upstreams should be asked for a new one after it has been sent.
+    Fresh { response: DNSMessage, refresh: bool },
+    /// An expired response that the upstreams failed to refresh, RFC 8767. `refresh` is `true`
+    /// if it is time to ask them again, after the response has been sent.
+    Stale { response: DNSMessage, refresh: bool },
+}
+
+/// What a response is cached under. Names are compared case-insensitively, RFC 4343, and
+/// responses to DNSSEC-aware queries carry records that other clients did not ask for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    /// `None` for an NXDOMAIN response, which holds for every type of the name, RFC 2308 5.
+    qtype: Option<RecordType>,
+    qclass: u16,
+    dnssec_ok: bool,
+}
+
+struct Entry {
+    response: DNSMessage,
+    /// When the response was received, to age its TTLs by.
+    stored: Instant,
+    /// When the record with the lowest TTL, or the negative TTL, runs out.
+    expires: Instant,
+    /// Until when the expired response is served without asking the upstreams, after they failed
+    /// to refresh it. `None` while they have not.
+    recheck: Option<Instant>,
+    /// The number of times the response has been served from the cache.
+    hits: u32,
+    /// Whether the response has already been handed out to be refreshed before it expires.
+    prefetching: bool,
+}
+
+/// One part of the cache, holding the keys that hash to it.
+struct Shard {
+    /// Where the slot of each key is in `slots`.
+    index: HashMap<CacheKey, usize>,
+    /// The entries in the order the clock hand sweeps them.
+    slots: Vec<Slot>,
+    /// The slot that is next considered for eviction.
+    hand: usize,
+    capacity: usize,
+}
+
+struct Slot {
+    key: CacheKey,
+    entry: Entry,
+    /// Set whenever the entry is used and cleared as the hand passes it, which spares the
+    /// entry from eviction once.
+    referenced: bool,
 }
 
 impl Cache {
     pub fn new(settings: &CacheSettings) -> Self {
+        let size = if settings.enabled { settings.size } else { 0 };
+        let shards = match settings.shards {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()) * 4,
+            shards => shards,
+        }
+        .min(size);
+
         Cache {
-            enabled: settings.enabled,
-            size: settings.size,
+            // Spread the size over the shards, the first ones taking the remainder.
+            shards: (0..shards)
+                .map(|i| Mutex::new(Shard::new(size / shards + usize::from(i < size % shards))))
+                .collect(),
+            hasher: RandomState::new(),
+            max_stale: Duration::from_secs(settings.max_stale),
+            stale_ttl: settings.stale_ttl,
+            prefetch_min_hits: settings.prefetch_min_hits,
+            prefetch_percent: settings.prefetch_percent.into(),
+        }
+    }
+
+    /// Looks up the response to a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the cached response with its TTLs lowered by the time it has been cached, or
+    /// `None` if there is no response or it has expired. An expired response is still returned
+    /// as stale while the upstreams are known to be failing. The ID has to be set by the caller.
+    pub fn query(&self, request: &DNSMessage) -> Option<Cached> {
+        self.lookup(request, false, Instant::now())
+    }
+
+    /// Looks up the response to a request after every upstream failed to answer it, RFC 8767.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the cached response, with its TTLs set to `stale_ttl` if it has expired, or `None`
+    /// if there is no response or it expired more than `max_stale` seconds ago. Until `stale_ttl`
+    /// has passed, `query` returns the stale response too rather than asking the upstreams again.
+    pub fn query_stale(&self, request: &DNSMessage) -> Option<DNSMessage> {
+        match self.lookup(request, true, Instant::now())? {
+            Cached::Fresh { response, .. } | Cached::Stale { response, .. } => Some(response),
         }
     }
 
-    pub fn query(&self, _domain: &str) -> Option<DNSMessage> {
+    /// Stores an upstream response, if it is an answer or a negative response that may be cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request that the response answers.
+    ///
+    /// * `response` - The upstream's response.
+    pub fn insert(&self, request: &DNSMessage, response: &DNSMessage) {
+        self.store(request, response, Instant::now());
+    }
+
+    fn store(&self, request: &DNSMessage, response: &DNSMessage, now: Instant) {
+        let Some(key) = self.key(request) else {
+            return;
+        };
+        if response.is_truncated() {
+            return;
+        }
+        let mut response = response.clone();
+        let (key, ttl) = match response.rcode() {
+            RCode::NOERROR if !response.answers().is_empty() => (key, response.min_ttl()),
+            // NODATA: the name exists, but has no records of the type.
+            RCode::NOERROR => (key, response.negative_ttl()),
+            RCode::NXDOMAIN if response.answers().is_empty() => {
+                (CacheKey { qtype: None, ..key }, response.negative_ttl())
+            }
+            // The name is an alias, and the NXDOMAIN is about the target of its CNAME chain, so it
+            // only holds for the type asked for, RFC 2308 5.
+            RCode::NXDOMAIN => {
+                let ttl = response.negative_ttl().zip(response.min_ttl());
+                (key, ttl.map(|(negative, records)| negative.min(records)))
+            }
+            _ => return,
+        };
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+        // Clients must not keep a negative response for longer than we do, RFC 2308 3.
+        if response.answers().is_empty() || response.rcode() == RCode::NXDOMAIN {
+            response.cap_ttls(ttl);
+        }
+
+        let entry = Entry {
+            response,
+            stored: now,
+            expires: now + Duration::from_secs(ttl.into()),
+            recheck: None,
+            hits: 0,
+            prefetching: false,
+        };
+        self.shard(&key).lock().unwrap().insert(key, entry);
+    }
+
+    fn lookup(&self, request: &DNSMessage, upstreams_failed: bool, now: Instant) -> Option<Cached> {
+        let key = self.key(request)?;
+        let name_key = CacheKey {
+            qtype: None,
+            ..key.clone()
+        };
+
+        for key in [key, name_key] {
+            let mut shard = self.shard(&key).lock().unwrap();
+            let Some(entry) = shard.get_mut(&key) else {
+                continue;
+            };
+            if entry.expires > now {
+                entry.hits = entry.hits.saturating_add(1);
+                // A popular response is refreshed once, near the end of its TTL, so that its
+                // clients never have to wait for the upstreams.
+                let lifetime = entry.expires - entry.stored;
+                let refresh = !entry.prefetching
+                    && self.prefetch_percent > 0
+                    && entry.hits >= self.prefetch_min_hits
+                    && entry.expires - now <= lifetime * self.prefetch_percent / 100;
+                entry.prefetching |= refresh;
+
+                let mut response = entry.response.clone();
+                let age = now.duration_since(entry.stored).as_secs();
+                drop(shard);
+
+                response.decrease_ttls(age.try_into().unwrap_or(u32::MAX));
+                return Some(Cached::Fresh { response, refresh });
+            }
+            if entry.expires + self.max_stale <= now {
+                shard.remove(&key);
+                continue;
+            }
+
+            // A stale response is only served once the upstreams have failed to refresh it, and
+            // they are asked again every `stale_ttl`, RFC 8767 5.
+            let refresh = match entry.recheck {
+                _ if upstreams_failed => false,
+                Some(recheck) if recheck > now => false,
+                Some(_) => true,
+                None => continue,
+            };
+            if upstreams_failed || refresh {
+                entry.recheck = Some(now + Duration::from_secs(self.stale_ttl.into()));
+            }
+            let mut response = entry.response.clone();
+            drop(shard);
+
+            response.set_ttls(self.stale_ttl);
+            return Some(Cached::Stale { response, refresh });
+        }
         None
     }
-}
\ No newline at end of file
+
+    fn shard(&self, key: &CacheKey) -> &Mutex<Shard> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// Builds the key for a request, or `None` if its response must not be cached.
+    fn key(&self, request: &DNSMessage) -> Option<CacheKey> {
+        // Only standard queries with a single question have a well-defined answer to reuse.
+        if self.shards.is_empty() || request.opcode() != 0 || request.question_count() != 1 {
+            return None;
+        }
+        Some(CacheKey {
+            name: request.qname_to_string().to_ascii_lowercase(),
+            qtype: Some(request.qtype()?),
+            qclass: request.qclass()?,
+            dnssec_ok: request.edns().is_some_and(|edns| edns.dnssec_ok()),
+        })
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Self {
+        Shard {
+            index: HashMap::with_capacity(capacity),
+            slots: Vec::with_capacity(capacity),
+            hand: 0,
+            capacity,
+        }
+    }
+
+    /// Gets an entry for updating and marks it as recently used.
+    fn get_mut(&mut self, key: &CacheKey) -> Option<&mut Entry> {
+        let slot = &mut self.slots[*self.index.get(key)?];
+        slot.referenced = true;
+        Some(&mut slot.entry)
+    }
+
+    /// Adds or replaces an entry. Once the shard is full, the hand sweeps past the entries used
+    /// since it last passed them and evicts the first one that has not been.
+    fn insert(&mut self, key: CacheKey, entry: Entry) {
+        if let Some(&i) = self.index.get(&key) {
+            self.slots[i].entry = entry;
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
+
+        let slot = Slot {
+            key: key.clone(),
+            entry,
+            referenced: false,
+        };
+        if self.slots.len() < self.capacity {
+            self.index.insert(key, self.slots.len());
+            self.slots.push(slot);
+            return;
+        }
+
+        while self.slots[self.hand].referenced {
+            self.slots[self.hand].referenced = false;
+            self.hand = (self.hand + 1) % self.slots.len();
+        }
+        let evicted = std::mem::replace(&mut self.slots[self.hand], slot);
+        self.index.remove(&evicted.key);
+        self.index.insert(key, self.hand);
+        self.hand = (self.hand + 1) % self.slots.len();
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        let Some(i) = self.index.remove(key) else {
+            return;
+        };
+        self.slots.swap_remove(i);
+        if let Some(moved) = self.slots.get(i) {
+            self.index.insert(moved.key.clone(), i);
+        }
+        if self.hand >= self.slots.len() {
+            self.hand = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{RData, ResourceRecord};
+    use std::net::Ipv4Addr;
+
+    fn cache() -> Cache {
+        Cache::new(&CacheSettings {
+            enabled: true,
+            size: 100,
+            shards: 1,
+            max_stale: 3600,
+            stale_ttl: 30,
+            prefetch_min_hits: 3,
+            prefetch_percent: 10,
+        })
+    }
+
+    fn request(name: &str, rtype: RecordType) -> DNSMessage {
+        DNSMessage::new_simple_query(name, rtype, 0).unwrap()
+    }
+
+    /// Builds an upstream response to `request` with a single A record.
+    fn answer(request: &DNSMessage, ttl: u32) -> DNSMessage {
+        let address = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+        let record = ResourceRecord::new(&request.qname_to_string(), ttl, address).unwrap();
+        DNSMessage::new_response(request, RCode::NOERROR, vec![record], Vec::new())
+    }
+
+    /// Builds a negative upstream response to `request` with an SOA record for its name.
+    fn negative(request: &DNSMessage, rcode: RCode, ttl: u32, minimum: u32) -> DNSMessage {
+        DNSMessage::new_response(request, rcode, Vec::new(), vec![soa(request, ttl, minimum)])
+    }
+
+    fn soa(request: &DNSMessage, ttl: u32, minimum: u32) -> ResourceRecord {
+        let rdata = RData::SOA {
+            mname: Vec::new(),
+            rname: Vec::new(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+        };
+        ResourceRecord::new(&request.qname_to_string(), ttl, rdata).unwrap()
+    }
+
+    fn fresh(cached: Option<Cached>) -> (DNSMessage, bool) {
+        match cached {
+            Some(Cached::Fresh { response, refresh }) => (response, refresh),
+            Some(Cached::Stale { .. }) => panic!("expected a fresh response, got a stale one"),
+            None => panic!("expected a fresh response, got none"),
+        }
+    }
+
+    fn stale(cached: Option<Cached>) -> (DNSMessage, bool) {
+        match cached {
+            Some(Cached::Stale { response, refresh }) => (response, refresh),
+            Some(Cached::Fresh { .. }) => panic!("expected a stale response, got a fresh one"),
+            None => panic!("expected a stale response, got none"),
+        }
+    }
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn ttls_age_while_cached() {
+        let cache = cache();
+        let request = request("example.com", RecordType::A);
+        let now = Instant::now();
+        cache.store(&request, &answer(&request, 300), now);
+
+        let (response, _) = fresh(cache.lookup(&request, false, now + seconds(100)));
+        assert_eq!(response.min_ttl(), Some(200));
+        // Names are matched case-insensitively.
+        let upper = self::request("EXAMPLE.com", RecordType::A);
+        assert!(cache.lookup(&upper, false, now + seconds(100)).is_some());
+
+        assert!(cache.lookup(&request, false, now + seconds(300)).is_none());
+    }
+
+    #[test]
+    fn nxdomain_holds_for_every_type_of_the_name() {
+        let cache = cache();
+        let a = request("missing.example", RecordType::A);
+        let now = Instant::now();
+        cache.store(&a, &negative(&a, RCode::NXDOMAIN, 3600, 60), now);
+
+        let aaaa = request("missing.example", RecordType::AAAA);
+        let (response, _) = fresh(cache.lookup(&aaaa, false, now));
+        assert_eq!(response.rcode(), RCode::NXDOMAIN);
+        // The SOA is capped to its MINIMUM field, RFC 2308 3.
+        assert_eq!(response.min_ttl(), Some(60));
+
+        assert!(cache.lookup(&aaaa, false, now + seconds(60)).is_none());
+    }
+
+    #[test]
+    fn nodata_only_holds_for_the_type_asked_for() {
+        let cache = cache();
+        let a = request("example.com", RecordType::A);
+        let now = Instant::now();
+        cache.store(&a, &negative(&a, RCode::NOERROR, 30, 3600), now);
+
+        let (response, _) = fresh(cache.lookup(&a, false, now));
+        assert!(response.answers().is_empty());
+        assert_eq!(response.min_ttl(), Some(30));
+        assert!(cache
+            .lookup(&request("example.com", RecordType::AAAA), false, now)
+            .is_none());
+
+        // Without an SOA record a negative response is not cached at all.
+        let other = request("other.example", RecordType::A);
+        let response = DNSMessage::new_response(&other, RCode::NOERROR, Vec::new(), Vec::new());
+        cache.store(&other, &response, now);
+        assert!(cache.lookup(&other, false, now).is_none());
+    }
+
+    #[test]
+    fn nxdomain_behind_a_cname_only_holds_for_the_type_asked_for() {
+        let cache = cache();
+        let a = request("alias.example", RecordType::A);
+        let target = ResourceRecord::new(
+            "alias.example",
+            300,
+            RData::CNAME(DNSMessage::parse_name("missing.example").unwrap()),
+        )
+        .unwrap();
+        let soa = soa(&request("missing.example", RecordType::A), 3600, 60);
+        let response = DNSMessage::new_response(&a, RCode::NXDOMAIN, vec![target], vec![soa]);
+        let now = Instant::now();
+        cache.store(&a, &response, now);
+
+        let (response, _) = fresh(cache.lookup(&a, false, now));
+        assert_eq!(response.rcode(), RCode::NXDOMAIN);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.min_ttl(), Some(60));
+        // The alias itself exists.
+        for rtype in [RecordType::CNAME, RecordType::AAAA] {
+            assert!(cache
+                .lookup(&request("alias.example", rtype), false, now)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn stale_responses_are_served_once_the_upstreams_fail() {
+        let cache = cache();
+        let request = request("example.com", RecordType::A);
+        let now = Instant::now();
+        cache.store(&request, &answer(&request, 10), now);
+
+        // An expired response is not used while the upstreams may still answer.
+        let expired = now + seconds(20);
+        assert!(cache.lookup(&request, false, expired).is_none());
+
+        let (response, _) = stale(cache.lookup(&request, true, expired));
+        assert_eq!(response.min_ttl(), Some(30));
+
+        // Until `stale_ttl` has passed, the stale response is served without asking again.
+        let (_, refresh) = stale(cache.lookup(&request, false, expired + seconds(29)));
+        assert!(!refresh);
+        let (_, refresh) = stale(cache.lookup(&request, false, expired + seconds(30)));
+        assert!(refresh);
+        let (_, refresh) = stale(cache.lookup(&request, false, expired + seconds(31)));
+        assert!(!refresh);
+
+        // Beyond `max_stale` the response is dropped for good.
+        let too_old = now + seconds(10 + 3600);
+        assert!(cache.lookup(&request, true, too_old).is_none());
+        assert!(cache.lookup(&request, true, expired).is_none());
+    }
+
+    #[test]
+    fn popular_responses_are_refreshed_once_before_they_expire() {
+        let cache = cache();
+        let request = request("example.com", RecordType::A);
+        let now = Instant::now();
+        cache.store(&request, &answer(&request, 100), now);
+
+        // Early hits only count towards the threshold.
+        for second in [1, 2] {
+            let (_, refresh) = fresh(cache.lookup(&request, false, now + seconds(second)));
+            assert!(!refresh);
+        }
+        let (_, refresh) = fresh(cache.lookup(&request, false, now + seconds(95)));
+        assert!(refresh);
+        let (_, refresh) = fresh(cache.lookup(&request, false, now + seconds(96)));
+        assert!(!refresh);
+
+        // A response that is rarely asked for is left to expire.
+        let rare = self::request("rare.example", RecordType::A);
+        cache.store(&rare, &answer(&rare, 100), now);
+        let (_, refresh) = fresh(cache.lookup(&rare, false, now + seconds(95)));
+        assert!(!refresh);
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            name: name.to_string(),
+            qtype: Some(RecordType::A),
+            qclass: 1,
+            dnssec_ok: false,
+        }
+    }
+
+    fn entry() -> Entry {
+        let now = Instant::now();
+        Entry {
+            response: request("example.com", RecordType::A),
+            stored: now,
+            expires: now,
+            recheck: None,
+            hits: 0,
+            prefetching: false,
+        }
+    }
+
+    #[test]
+    fn clock_spares_recently_used_entries_once() {
+        let mut shard = Shard::new(2);
+        shard.insert(key("a"), entry());
+        shard.insert(key("b"), entry());
+        assert!(shard.get_mut(&key("a")).is_some());
+
+        // The hand passes over `a`, clearing its mark, and evicts `b`.
+        shard.insert(key("c"), entry());
+        assert!(shard.index.contains_key(&key("a")));
+        assert!(!shard.index.contains_key(&key("b")));
+
+        // `a` has not been used since, so it goes next.
+        shard.insert(key("d"), entry());
+        assert!(!shard.index.contains_key(&key("a")));
+        assert!(shard.index.contains_key(&key("c")));
+        assert!(shard.index.contains_key(&key("d")));
+    }
+
+    #[test]
+    fn removing_an_entry_updates_the_one_moved_into_its_slot() {
+        let mut shard = Shard::new(3);
+        for name in ["a", "b", "c"] {
+            shard.insert(key(name), entry());
+        }
+        shard.hand = 2;
+
+        shard.remove(&key("a"));
+        assert_eq!(shard.index.len(), 2);
+        for (name, slot) in [("c", 0), ("b", 1)] {
+            assert_eq!(shard.index[&key(name)], slot);
+            assert_eq!(shard.slots[slot].key, key(name));
+        }
+        // The hand is kept within the shorter list of slots.
+        assert_eq!(shard.hand, 0);
+
+        shard.remove(&key("b"));
+        assert_eq!(shard.index[&key("c")], 0);
+        assert_eq!(shard.slots.len(), 1);
+    }
+}
@@ -1,8 +1,64 @@
-#[derive(Debug)]
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The largest offset a compression pointer can refer to (14 bits).
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+/// The maximum number of compression pointers followed while parsing a single name.
+const MAX_POINTER_HOPS: usize = 64;
+/// The maximum length of a single label, RFC 1035 2.3.4.
+const MAX_LABEL_LENGTH: usize = 63;
+/// The maximum length of a domain name in wire format, RFC 1035 2.3.4.
+const MAX_NAME_LENGTH: usize = 255;
+/// The largest UDP message that may be sent to a client that does not support EDNS, RFC 1035 2.3.4.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+/// The UDP payload size we advertise and accept, as recommended by DNS Flag Day 2020.
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Errors raised while decoding a DNS message from the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The message ended before a complete field could be read.
+    Truncated,
+    /// A label in a name given as text was empty or held an invalid escape.
+    BadLabel,
+    /// A compression pointer did not point strictly backwards, or too many were chained.
+    PointerLoop,
+    /// A label was longer than 63 octets, or used one of the unassigned label types.
+    LabelTooLong,
+    /// A domain name was longer than 255 octets.
+    NameTooLong,
+    /// The header, together with the OPT pseudo-record, carried an RCODE that is not defined.
+    BadRcode(u16),
+    /// The RDATA did not match the layout of its record type.
+    BadRdata,
+    /// An OPT pseudo-record had a non-root owner name, or more than one was present.
+    BadOpt,
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Truncated => write!(f, "Unexpected end of data"),
+            DnsError::BadLabel => write!(f, "Invalid label in domain name"),
+            DnsError::PointerLoop => write!(f, "Invalid compression pointer in domain name"),
+            DnsError::LabelTooLong => write!(f, "Label exceeds {} octets", MAX_LABEL_LENGTH),
+            DnsError::NameTooLong => write!(f, "Domain name exceeds {} octets", MAX_NAME_LENGTH),
+            DnsError::BadRcode(value) => write!(f, "Invalid RCODE value {}", value),
+            DnsError::BadRdata => write!(f, "Invalid RDATA for record type"),
+            DnsError::BadOpt => write!(f, "Invalid OPT pseudo-record"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     /// The header section of the message.
     header: MessageHeader,
     /// A flag indicating whether domain name compression is used in the message.
+    /// Parsed messages always set this so that they are compressed again when re-emitted.
     compress: bool,
     /// Questions are queries the client has for the server.
     question: Vec<Question>,
@@ -12,9 +68,11 @@ pub struct Message {
     authority: Vec<ResourceRecord>,
     /// Additional records contain extra information that may be helpful in processing the response.
     extra: Vec<ResourceRecord>,
+    /// The EDNS(0) parameters from the OPT pseudo-record, which is kept out of `extra`.
+    edns: Option<Edns>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 struct MessageHeader {
     /// Assigned by the program that generates any kind of query.
     /// This identifier is copied into the response.
@@ -34,6 +92,8 @@ struct MessageHeader {
     /// Reserved for future use. Must be zero in all queries and responses.
     z: u8,
     /// Response code - set as part of responses and indicates success or failure of the query.
+    /// Holds the whole 12-bit extended RCODE, whose upper 8 bits travel in the OPT pseudo-record,
+    /// RFC 6891 6.1.3, and are lost if the message has no EDNS(0).
     rcode: RCode,
     /// The number of entries in the question section.
     qdcount: u16,
@@ -45,27 +105,31 @@ struct MessageHeader {
     arcount: u16,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 struct Question {
     /// The domain name that is the subject of the query.
-    qname: Vec<String>,
+    qname: Vec<Vec<u8>>,
     /// Specifies the type of the query.
     qtype: u16,
     /// Specifies the class of the query.
     qclass: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ResourceRecord {
-    name: Vec<String>,
+    /// The domain name to which this resource record pertains.
+    name: Vec<Vec<u8>>,
+    /// Specifies the meaning of the data in the RDATA field.
     rtype: RecordType,
+    /// Specifies the class of the data in the RDATA field.
     rclass: u16,
+    /// The time interval in seconds that the resource record may be cached.
     ttl: u32,
-    rdlength: u16,
-    rdata: Vec<u8>,
+    /// The data of the resource record. RDLENGTH is derived from this when serializing.
+    rdata: RData,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum RecordType {
     A,     // = 1, RFC 1035
     AAAA,  // = 28, RFC 3596
@@ -76,9 +140,92 @@ pub enum RecordType {
     SOA,   // = 6, RFC 1035
     SRV,   // = 33, RFC 2782
     TXT,   // = 16, RFC 1035
+    OPT,   // = 41, RFC 6891
+    /// Any record type not listed above, kept as its numeric value.
+    Unknown(u16),
+}
+
+/// The typed contents of a resource record's RDATA field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    /// A host address, RFC 1035
+    A(Ipv4Addr),
+    /// An IPv6 host address, RFC 3596
+    AAAA(Ipv6Addr),
+    /// The canonical name for an alias, RFC 1035
+    CNAME(Vec<Vec<u8>>),
+    /// A mail exchange, RFC 1035
+    MX {
+        /// The preference given to this record among others at the same owner. Lower values are preferred.
+        preference: u16,
+        /// The host willing to act as a mail exchange for the owner name.
+        exchange: Vec<Vec<u8>>,
+    },
+    /// An authoritative name server, RFC 1035
+    NS(Vec<Vec<u8>>),
+    /// A domain name pointer, RFC 1035
+    PTR(Vec<Vec<u8>>),
+    /// The start of a zone of authority, RFC 1035
+    SOA {
+        /// The name server that was the original or primary source of data for this zone.
+        mname: Vec<Vec<u8>>,
+        /// The mailbox of the person responsible for this zone.
+        rname: Vec<Vec<u8>>,
+        /// The version number of the original copy of the zone.
+        serial: u32,
+        /// The interval in seconds before the zone should be refreshed.
+        refresh: u32,
+        /// The interval in seconds that should elapse before a failed refresh should be retried.
+        retry: u32,
+        /// The upper limit in seconds before the zone is no longer authoritative.
+        expire: u32,
+        /// The TTL in seconds used for negative responses from this zone, RFC 2308.
+        minimum: u32,
+    },
+    /// The location of a service, RFC 2782
+    SRV {
+        /// The priority of this target host. Lower values are preferred.
+        priority: u16,
+        /// A relative weight for entries with the same priority.
+        weight: u16,
+        /// The port on the target host of this service.
+        port: u16,
+        /// The domain name of the target host.
+        target: Vec<Vec<u8>>,
+    },
+    /// One or more character strings, RFC 1035
+    TXT(Vec<Vec<u8>>),
+    /// The options of an EDNS(0) pseudo-record, RFC 6891
+    OPT(Vec<EdnsOption>),
+    /// The raw data of a record type without a dedicated variant.
+    Unknown(Vec<u8>),
+}
+
+/// The EDNS(0) parameters carried in the fixed fields of an OPT pseudo-record, RFC 6891.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edns {
+    /// The largest UDP payload the sender is able to reassemble, carried in the CLASS field.
+    udp_payload_size: u16,
+    /// The EDNS version implemented by the sender.
+    version: u8,
+    /// DNSSEC OK - set to indicate that the sender is able to accept DNSSEC records, RFC 3225.
+    dnssec_ok: bool,
+    /// The remaining flag bits. Reserved for future use, but preserved as received.
+    z: u16,
+    /// The options carried in the RDATA.
+    options: Vec<EdnsOption>,
+}
+
+/// A single option from the RDATA of an OPT pseudo-record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdnsOption {
+    /// Identifies the option, e.g. 10 for COOKIE.
+    code: u16,
+    /// The option's data, which is not interpreted.
+    data: Vec<u8>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum RCode {
     /// DNS Query completed successfully
@@ -101,6 +248,10 @@ pub enum RCode {
     NOTAUTH = 9,
     /// Name not in zone
     NOTZONE = 10,
+    /// The EDNS version of the request is not supported, RFC 6891
+    BADVERS = 16,
+    /// The server cookie is missing or invalid, RFC 7873
+    BADCOOKIE = 23,
 }
 
 impl Message {
@@ -121,7 +272,7 @@ impl Message {
                 qr: 1,
                 opcode: request.header.opcode,
                 aa: 1, // review this later
-                tc: 0, // review this later
+                tc: 0, // Set by serialize_truncated if the response does not fit
                 rd: request.header.rd,
                 ra: 0, // review this later
                 z: 0,
@@ -131,11 +282,12 @@ impl Message {
                 nscount: 0, // review this later
                 arcount: 0, // review this later
             },
-            compress: false,
+            compress: true,
             question: request.question.clone(),
             answer: Vec::new(),
             authority: Vec::new(),
             extra: Vec::new(),
+            edns: request.edns.as_ref().map(Edns::new_response),
         }
     }
 
@@ -166,12 +318,266 @@ impl Message {
                 nscount: 0, // No authority records in this example; adjust if you include them
                 arcount: 0, // No additional records
             },
-            compress: false,
+            compress: true,
             question: request.question.clone(), // Echo back the question section
             answer: Vec::new(), // No answer section for NXDOMAIN
             authority: Vec::new(), // Optionally, include SOA record in authority section
             extra: Vec::new(),
+            edns: request.edns.as_ref().map(Edns::new_response), // Answer EDNS with EDNS
+        }
+    }
+
+    /// Creates a query that forwards the questions of a client's request to an upstream server.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request.
+    ///
+    /// * `id` - The transaction ID of the new query, which should be unpredictable.
+    ///
+    /// # Returns
+    ///
+    /// A new DNS query with recursion desired, advertising our own EDNS(0) parameters.
+    pub fn new_query(request: &Message, id: u16) -> Self {
+        let dnssec_ok = request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        Message {
+            header: MessageHeader {
+                id,
+                qr: 0,
+                opcode: request.header.opcode,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: request.header.z & 0x1, // Keep the CD bit, RFC 4035 3.2.2
+                rcode: RCode::NOERROR,
+                qdcount: request.header.qdcount,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            compress: true,
+            question: request.question.clone(),
+            answer: Vec::new(),
+            authority: Vec::new(),
+            extra: Vec::new(),
+            edns: Some(Edns::new(dnssec_ok)),
+        }
+    }
+
+    /// Creates a recursive query for a single name and type in the IN class.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The domain name to query, in dotted form. A trailing dot is optional.
+    ///
+    /// * `rtype` - The record type to query.
+    ///
+    /// * `id` - The transaction ID of the new query.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new DNS query, or a `DnsError` if the name is not a valid domain name.
+    pub fn new_simple_query(name: &str, rtype: RecordType, id: u16) -> Result<Self, DnsError> {
+        Ok(Message {
+            header: MessageHeader {
+                id,
+                qr: 0,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: 0,
+                rcode: RCode::NOERROR,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            compress: true,
+            question: vec![Question {
+                qname: Self::parse_name(name)?,
+                qtype: rtype.to_u16(),
+                qclass: 1, // IN
+            }],
+            answer: Vec::new(),
+            authority: Vec::new(),
+            extra: Vec::new(),
+            edns: None,
+        })
+    }
+
+    /// Creates a new DNS "server failure" (SERVFAIL) response based on the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request message to which this response corresponds.
+    ///
+    /// # Returns
+    ///
+    /// A new DNS message configured as a "server failure" response.
+    pub fn new_server_failure_response(request: &Message) -> Self {
+        let mut response = Message::new(request);
+        response.header.aa = 0;
+        response.header.ra = 1;
+        response.header.rcode = RCode::SERVFAIL;
+        response
+    }
+
+    /// Creates a response to a request with the given records, as a recursive server sends it.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request message to which this response corresponds.
+    ///
+    /// * `rcode` - The response code.
+    ///
+    /// * `answers` - The records of the answer section.
+    ///
+    /// * `authorities` - The records of the authority section, e.g. the SOA record of a negative response.
+    ///
+    /// # Returns
+    ///
+    /// A new DNS message with recursion available and the request's ID, question and EDNS(0) support.
+    pub fn new_response(
+        request: &Message,
+        rcode: RCode,
+        answers: Vec<ResourceRecord>,
+        authorities: Vec<ResourceRecord>,
+    ) -> Self {
+        let mut response = Message::new(request);
+        response.header.aa = 0;
+        response.header.ra = 1;
+        response.header.rcode = rcode;
+        response.answer = answers;
+        response.authority = authorities;
+        response
+    }
+
+    /// Creates a "format error" (FORMERR) response to a request that could not be parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes of the request.
+    ///
+    /// # Returns
+    ///
+    /// A response with the request's ID, opcode and RD flag and empty sections, or `None` if the
+    /// data does not begin with the header of a query, which is then best left unanswered.
+    pub fn new_format_error_response(data: &[u8]) -> Option<Self> {
+        let request = MessageHeader::deserialize(data).ok()?;
+        if request.qr != 0 {
+            return None;
         }
+        Some(Message {
+            header: MessageHeader {
+                qr: 1,
+                aa: 0,
+                tc: 0,
+                ra: 1,
+                z: 0,
+                rcode: RCode::FORMERR,
+                qdcount: 0,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+                ..request
+            },
+            compress: true,
+            question: Vec::new(),
+            answer: Vec::new(),
+            authority: Vec::new(),
+            extra: Vec::new(),
+            edns: None,
+        })
+    }
+
+    /// Readdresses a response obtained elsewhere, e.g. from an upstream, to a client's request.
+    ///
+    /// The transaction ID, RD flag and question are copied from the request, so the client sees
+    /// its own spelling of the name, and the OPT pseudo-record is replaced by our own so that the
+    /// client never sees the upstream's EDNS parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request that this message answers.
+    pub fn set_reply_to(&mut self, request: &Message) {
+        self.header.id = request.header.id;
+        self.header.rd = request.header.rd;
+        self.question = request.question.clone();
+        self.edns = request.edns.as_ref().map(Edns::new_response);
+    }
+
+    /// Checks whether this message is a response to the given query.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query that was sent.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the message is a response with the query's ID and the same questions.
+    pub fn is_response_to(&self, query: &Message) -> bool {
+        self.header.qr == 1
+            && self.header.id == query.header.id
+            && self.question.len() == query.question.len()
+            && self.question.iter().zip(&query.question).all(|(a, b)| a.matches(b))
+    }
+
+    /// Gets the transaction ID of the message.
+    pub fn id(&self) -> u16 {
+        self.header.id
+    }
+
+    /// Gets whether the message is a response (QR=1) rather than a query.
+    pub fn is_response(&self) -> bool {
+        self.header.qr == 1
+    }
+
+    /// Gets whether the message was truncated and should be retried over TCP.
+    pub fn is_truncated(&self) -> bool {
+        self.header.tc == 1
+    }
+
+    /// Gets the kind of query in this message, 0 for a standard query (QUERY).
+    pub fn opcode(&self) -> u8 {
+        self.header.opcode
+    }
+
+    /// Gets the response code of the message, including the extended RCODE bits of EDNS(0).
+    pub fn rcode(&self) -> RCode {
+        self.header.rcode
+    }
+
+    /// Gets whether the sender asked for the query to be resolved recursively.
+    pub fn is_recursion_desired(&self) -> bool {
+        self.header.rd == 1
+    }
+
+    /// Gets whether the responding server supports recursive queries.
+    pub fn is_recursion_available(&self) -> bool {
+        self.header.ra == 1
+    }
+
+    /// Gets whether the responding server validated the answer with DNSSEC, RFC 4035 3.2.3.
+    pub fn is_authentic_data(&self) -> bool {
+        self.header.z & 0x2 != 0
+    }
+
+    /// Gets whether the sender asked for DNSSEC validation to be skipped, RFC 4035 3.2.2.
+    pub fn is_checking_disabled(&self) -> bool {
+        self.header.z & 0x1 != 0
+    }
+
+    /// Sets or clears the CD bit, asking an upstream to skip DNSSEC validation.
+    pub fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.header.z = (self.header.z & !0x1) | checking_disabled as u8;
+    }
+
+    /// Replaces the EDNS(0) parameters of the message, or removes them with `None`.
+    pub fn set_edns(&mut self, edns: Option<Edns>) {
+        self.edns = edns;
     }
 
     /// Serializes a DNS message to a byte vector.
@@ -184,21 +590,92 @@ impl Message {
     ///
     /// A byte vector containing the serialized DNS message.
     pub fn serialize(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.header.serialize());
+        // The section counts are taken from the sections themselves so that
+        // records added after the header was built are not lost on the wire.
+        let header = MessageHeader {
+            qdcount: self.question.len() as u16,
+            ancount: self.answer.len() as u16,
+            nscount: self.authority.len() as u16,
+            arcount: (self.extra.len() + self.edns.is_some() as usize) as u16,
+            ..self.header
+        };
+
+        let mut encoder = Encoder::new(self.compress);
+        encoder.bytes.extend_from_slice(&header.serialize());
         for question in &self.question {
-            bytes.extend_from_slice(&question.serialize());
+            question.serialize(&mut encoder);
         }
         for answer in &self.answer {
-            bytes.extend_from_slice(&answer.serialize());
+            answer.serialize(&mut encoder);
         }
         for authority in &self.authority {
-            bytes.extend_from_slice(&authority.serialize());
+            authority.serialize(&mut encoder);
         }
         for extra in &self.extra {
-            bytes.extend_from_slice(&extra.serialize());
+            extra.serialize(&mut encoder);
         }
-        bytes
+        if let Some(edns) = &self.edns {
+            edns.to_record(self.header.rcode).serialize(&mut encoder);
+        }
+        encoder.bytes
+    }
+
+    /// Serializes a DNS message to a byte vector no longer than `max_size` bytes.
+    ///
+    /// The additional section is dropped first, as its records are optional (RFC 2181 9). If the
+    /// message still does not fit, only the question and OPT pseudo-record are kept and the TC bit
+    /// is set, so that the client retries over TCP rather than caching a partial answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `self` - The DNS message to be serialized.
+    ///
+    /// * `max_size` - The largest message the receiver accepts, e.g. from `max_udp_response_size`.
+    ///
+    /// # Returns
+    ///
+    /// A byte vector containing the serialized, possibly truncated, DNS message.
+    pub fn serialize_truncated(&self, max_size: usize) -> Vec<u8> {
+        let bytes = self.serialize();
+        if bytes.len() <= max_size {
+            return bytes;
+        }
+
+        let mut truncated = Message {
+            header: self.header,
+            compress: self.compress,
+            question: self.question.clone(),
+            answer: self.answer.clone(),
+            authority: self.authority.clone(),
+            extra: Vec::new(),
+            edns: self.edns.clone(),
+        };
+        let bytes = truncated.serialize();
+        if bytes.len() <= max_size {
+            return bytes;
+        }
+
+        truncated.answer.clear();
+        truncated.authority.clear();
+        truncated.header.tc = 1;
+        truncated.serialize()
+    }
+
+    /// Serializes a DNS message with the two byte length prefix used over streams, RFC 7766 8.
+    ///
+    /// # Arguments
+    ///
+    /// * `self` - The DNS message to be serialized.
+    ///
+    /// # Returns
+    ///
+    /// A byte vector containing the length and the message, truncated to fit the length if needed.
+    pub fn serialize_framed(&self) -> Vec<u8> {
+        let bytes = self.serialize_truncated(u16::MAX as usize);
+        let mut frame = Vec::with_capacity(bytes.len() + 2);
+        frame.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&bytes);
+        frame
     }
 
     /// Deserializes a DNS message from a byte slice.
@@ -209,11 +686,11 @@ impl Message {
     ///
     /// # Returns
     ///
-    /// A `Result` containing the deserialized `Message` if successful, or an `std::io::Error` if deserialization fails.
-    pub fn deserialize(data: &[u8]) -> Result<Self, std::io::Error> {
+    /// A `Result` containing the deserialized `Message` if successful, or a `DnsError` if deserialization fails.
+    pub fn deserialize(data: &[u8]) -> Result<Self, DnsError> {
         let mut offset = 0;
 
-        let header = MessageHeader::deserialize(&data[offset..])?;
+        let mut header = MessageHeader::deserialize(&data[offset..])?;
         offset += 12; // Header is 12 bytes
 
         let mut question = Vec::new();
@@ -223,17 +700,74 @@ impl Message {
             question.push(q);
         }
 
+        let (answer, offset) = Self::parse_records(data, offset, header.ancount)?;
+        let (authority, offset) = Self::parse_records(data, offset, header.nscount)?;
+        let (records, _) = Self::parse_records(data, offset, header.arcount)?;
+
+        // The OPT pseudo-record is lifted out of the additional section, RFC 6891 6.1.1.
+        let mut extra = Vec::with_capacity(records.len());
+        let mut edns = None;
+        for record in records {
+            if record.rtype != RecordType::OPT {
+                extra.push(record);
+            } else if edns.is_none() {
+                let (parameters, extended_rcode) = Edns::from_record(record)?;
+                edns = Some(parameters);
+                if extended_rcode != 0 {
+                    let value = (extended_rcode as u16) << 4 | header.rcode.to_u8() as u16;
+                    header.rcode = u8::try_from(value)
+                        .ok()
+                        .and_then(RCode::from_u8)
+                        .ok_or(DnsError::BadRcode(value))?;
+                }
+            } else {
+                return Err(DnsError::BadOpt);
+            }
+        }
+
         Ok(Message {
             header,
-            compress: false,
+            compress: true,
             question,
-            answer: Vec::new(),
-            authority: Vec::new(),
-            extra: Vec::new(),
+            answer,
+            authority,
+            extra,
+            edns,
         })
     }
 
-    /// Parses a QNAME from a byte slice.
+    /// Parses a run of resource records from a byte slice.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The byte slice containing the DNS message.
+    ///
+    /// * `start_offset` - The offset in the byte slice where the first record starts.
+    ///
+    /// * `count` - The number of records to parse, as given by the header.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with the parsed records and the new offset in the byte slice if successful, or a `DnsError` if parsing fails.
+    fn parse_records(
+        data: &[u8],
+        start_offset: usize,
+        count: u16,
+    ) -> Result<(Vec<ResourceRecord>, usize), DnsError> {
+        let mut offset = start_offset;
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (record, new_offset) = ResourceRecord::deserialize(data, offset)?;
+            offset = new_offset;
+            records.push(record);
+        }
+        Ok((records, offset))
+    }
+
+    /// Parses a QNAME from a byte slice, following compression pointers (RFC 1035 4.1.4).
+    ///
+    /// Pointers may only refer to data before themselves, which rules out loops, and at most
+    /// `MAX_POINTER_HOPS` of them are followed for a single name.
     ///
     /// # Arguments
     ///
@@ -243,27 +777,227 @@ impl Message {
     ///
     /// # Returns
     ///
-    /// A `Result` containing a tuple with the QNAME as a vector of strings and the new offset in the byte slice if successful, or an `std::io::Error` if parsing fails.
+    /// A `Result` containing a tuple with the labels of the QNAME and the new offset in the byte slice if successful, or a `DnsError` if parsing fails.
     fn parse_qname(
         data: &[u8],
         start_offset: usize,
-    ) -> Result<(Vec<String>, usize), std::io::Error> {
+    ) -> Result<(Vec<Vec<u8>>, usize), DnsError> {
         let mut offset = start_offset;
         let mut labels = Vec::new();
-        let mut length = data[offset] as usize;
+        // The offset just past the name in the original position, set once the first pointer is taken.
+        let mut end_offset = None;
+        let mut hops = 0;
+        // The length of the name in wire format, including the terminating null byte.
+        let mut name_length = 1;
+        let mut length = *data.get(offset).ok_or(DnsError::Truncated)? as usize;
 
         while length > 0 {
+            match length & 0xC0 {
+                0xC0 => {
+                    let low = *data.get(offset + 1).ok_or(DnsError::Truncated)? as usize;
+                    let pointer = ((length & 0x3F) << 8) | low;
+                    hops += 1;
+                    if pointer >= offset || hops > MAX_POINTER_HOPS {
+                        return Err(DnsError::PointerLoop);
+                    }
+                    end_offset.get_or_insert(offset + 2);
+                    offset = pointer;
+                    length = data[offset] as usize;
+                    continue;
+                }
+                // The extended (0x40) and reserved (0x80) label types are not in use, so these
+                // lengths can only be labels over the 63 octet limit.
+                0x40 | 0x80 => return Err(DnsError::LabelTooLong),
+                _ => {}
+            }
+
+            name_length += length + 1;
+            if name_length > MAX_NAME_LENGTH {
+                return Err(DnsError::NameTooLong);
+            }
+
             offset += 1; // Move past the length byte
-            let label = std::str::from_utf8(&data[offset..offset + length]).map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid QNAME")
-            })?;
-            labels.push(label.to_string());
+            // Labels may hold any octet, RFC 2181 11.
+            let label = data.get(offset..offset + length).ok_or(DnsError::Truncated)?;
+            labels.push(label.to_vec());
 
             offset += length; // Move past the current label
-            length = data[offset] as usize; // Length of the next label
+            length = *data.get(offset).ok_or(DnsError::Truncated)? as usize; // Length of the next label
+        }
+
+        // Return the labels and the new offset (after the null byte or the first pointer)
+        Ok((labels, end_offset.unwrap_or(offset + 1)))
+    }
+
+    /// Splits a domain name in presentation format into labels, enforcing the label and name
+    /// length limits. Within a label, a backslash escapes the next character and `\DDD` stands
+    /// for the octet with the decimal value DDD, RFC 1035 5.1.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The domain name in dotted form. Both `"."` and `""` denote the root.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the labels of the name, or a `DnsError` if the name is not valid.
+    pub fn parse_name(name: &str) -> Result<Vec<Vec<u8>>, DnsError> {
+        if name.is_empty() || name == "." {
+            return Ok(Vec::new());
         }
 
-        Ok((labels, offset + 1)) // Return the labels and the new offset (after the null byte)
+        let mut name_length = 1;
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut bytes = name.bytes();
+        loop {
+            let byte = bytes.next();
+            match byte {
+                Some(b'\\') => {
+                    let escaped = bytes.next().ok_or(DnsError::BadLabel)?;
+                    if escaped.is_ascii_digit() {
+                        let digits = [
+                            escaped,
+                            bytes.next().unwrap_or(0),
+                            bytes.next().unwrap_or(0),
+                        ];
+                        let value = std::str::from_utf8(&digits)
+                            .ok()
+                            .and_then(|digits| digits.parse::<u8>().ok())
+                            .ok_or(DnsError::BadLabel)?;
+                        label.push(value);
+                    } else {
+                        label.push(escaped);
+                    }
+                    continue;
+                }
+                Some(b'.') | None => {}
+                Some(byte) => {
+                    label.push(byte);
+                    continue;
+                }
+            }
+
+            // A trailing dot ends a fully qualified name.
+            if byte.is_none() && label.is_empty() && !labels.is_empty() {
+                break;
+            }
+            if label.is_empty() {
+                return Err(DnsError::BadLabel);
+            }
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(DnsError::LabelTooLong);
+            }
+            name_length += label.len() + 1;
+            if name_length > MAX_NAME_LENGTH {
+                return Err(DnsError::NameTooLong);
+            }
+            labels.push(std::mem::take(&mut label));
+            if byte.is_none() {
+                break;
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Gets the EDNS(0) parameters of the message.
+    ///
+    /// # Returns
+    ///
+    /// The parameters from the message's OPT pseudo-record, or `None` if the sender does not support EDNS.
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    /// Gets the records of the answer section.
+    pub fn answers(&self) -> &[ResourceRecord] {
+        &self.answer
+    }
+
+    /// Gets the records of the authority section.
+    pub fn authorities(&self) -> &[ResourceRecord] {
+        &self.authority
+    }
+
+    /// Gets the lowest TTL of the records in the answer and authority sections.
+    ///
+    /// # Returns
+    ///
+    /// The number of seconds the message may be cached for, or `None` if it has no records.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answer
+            .iter()
+            .chain(&self.authority)
+            .map(|record| record.ttl)
+            .min()
+    }
+
+    /// Lowers the TTL of every record by the time the message has been held, e.g. in a cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `seconds` - The number of seconds since the message was received. TTLs stop at zero.
+    pub fn decrease_ttls(&mut self, seconds: u32) {
+        for record in self.records_mut() {
+            record.ttl = record.ttl.saturating_sub(seconds);
+        }
+    }
+
+    /// Sets the TTL of every record, e.g. of a stale response, RFC 8767 4.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - The number of seconds the records may be cached for.
+    pub fn set_ttls(&mut self, ttl: u32) {
+        for record in self.records_mut() {
+            record.ttl = ttl;
+        }
+    }
+
+    /// Lowers the TTL of every record to at most `max_ttl`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_ttl` - The longest any record may be cached for, e.g. from `negative_ttl`.
+    pub fn cap_ttls(&mut self, max_ttl: u32) {
+        for record in self.records_mut() {
+            record.ttl = record.ttl.min(max_ttl);
+        }
+    }
+
+    /// Gets how long a negative response, NXDOMAIN or NODATA, may be cached, RFC 2308 5.
+    ///
+    /// # Returns
+    ///
+    /// The lower of the TTL and the MINIMUM field of the SOA record in the authority section, or
+    /// `None` if there is no SOA record, in which case the response should not be cached.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authority.iter().find_map(|record| match record.rdata {
+            RData::SOA { minimum, .. } => Some(record.ttl.min(minimum)),
+            _ => None,
+        })
+    }
+
+    /// Gets the records of the answer, authority and additional sections for updating.
+    fn records_mut(&mut self) -> impl Iterator<Item = &mut ResourceRecord> {
+        self.answer
+            .iter_mut()
+            .chain(&mut self.authority)
+            .chain(&mut self.extra)
+    }
+
+    /// Gets the size of the largest response that may be sent to the sender of this message over UDP.
+    ///
+    /// # Returns
+    ///
+    /// The sender's advertised UDP payload size, limited to what we are willing to send, or 512 without EDNS.
+    pub fn max_udp_response_size(&self) -> usize {
+        let size = match &self.edns {
+            Some(edns) => edns
+                .udp_payload_size
+                .clamp(DEFAULT_UDP_PAYLOAD_SIZE, EDNS_UDP_PAYLOAD_SIZE),
+            None => DEFAULT_UDP_PAYLOAD_SIZE,
+        };
+        size as usize
     }
 
     /// Gets the number of question in the message.
@@ -286,10 +1020,28 @@ impl Message {
     /// An `Option<String>` containing the dot-separated domain name if the index is valid, or `None` if the index is out of bounds.
     pub fn qname_to_string(&self) -> String {
         self.question
-            .get(0)
-            .map(|q| q.qname.join("."))
+            .first()
+            .map(|q| labels_to_string(&q.qname))
             .unwrap_or_else(|| "default_value".to_string())
     }
+
+    /// Gets the record type asked for by the first question.
+    ///
+    /// # Returns
+    ///
+    /// The type of the first question, or `None` if the message has no questions.
+    pub fn qtype(&self) -> Option<RecordType> {
+        self.question.first().map(|q| RecordType::from_u16(q.qtype))
+    }
+
+    /// Gets the class asked for by the first question.
+    ///
+    /// # Returns
+    ///
+    /// The class of the first question, e.g. 1 for IN, or `None` if the message has no questions.
+    pub fn qclass(&self) -> Option<u16> {
+        self.question.first().map(|q| q.qclass)
+    }
 }
 
 impl MessageHeader {
@@ -308,7 +1060,8 @@ impl MessageHeader {
         let mut flag_bytes = [0u8; 2];
         flag_bytes[0] =
             (self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd;
-        flag_bytes[1] = (self.ra << 7) | (self.z << 4) | self.rcode.to_u8();
+        // Only the lower 4 bits of an extended RCODE fit the header.
+        flag_bytes[1] = (self.ra << 7) | (self.z << 4) | (self.rcode.to_u8() & 0x0F);
         bytes.extend_from_slice(&flag_bytes);
         bytes.extend_from_slice(&self.qdcount.to_be_bytes());
         bytes.extend_from_slice(&self.ancount.to_be_bytes());
@@ -325,20 +1078,14 @@ impl MessageHeader {
     ///
     /// # Returns
     ///
-    /// A `Result` containing the deserialized `MessageHeader` if successful, or an `std::io::Error` if deserialization fails.
-    fn deserialize(data: &[u8]) -> Result<Self, std::io::Error> {
+    /// A `Result` containing the deserialized `MessageHeader` if successful, or a `DnsError` if deserialization fails.
+    fn deserialize(data: &[u8]) -> Result<Self, DnsError> {
         if data.len() < 12 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Message header is too short",
-            ));
+            return Err(DnsError::Truncated);
         }
 
         let rcode_value = data[3] & 0x0F; // Extract the lower 4 bits for RCODE
-        let rcode = RCode::from_u8(rcode_value).ok_or(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid RCODE value",
-        ))?;
+        let rcode = RCode::from_u8(rcode_value).ok_or(DnsError::BadRcode(rcode_value.into()))?;
 
         Ok(MessageHeader {
             id: u16::from_be_bytes([data[0], data[1]]),
@@ -359,25 +1106,30 @@ impl MessageHeader {
 }
 
 impl Question {
-    /// Serializes a question to a byte vector.
+    /// Checks whether two questions ask for the same name, type and class.
+    /// Names are compared case-insensitively, RFC 4343.
+    fn matches(&self, other: &Question) -> bool {
+        self.qtype == other.qtype
+            && self.qclass == other.qclass
+            && self.qname.len() == other.qname.len()
+            && self
+                .qname
+                .iter()
+                .zip(&other.qname)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Serializes a question into an encoder.
     ///
     /// # Arguments
     ///
     /// * `self` - The question to be serialized.
     ///
-    /// # Returns
-    ///
-    /// A byte vector containing the serialized question.
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for label in &self.qname {
-            bytes.push(label.len() as u8); // Length octet
-            bytes.extend_from_slice(label.as_bytes()); // Label octets
-        }
-        bytes.push(0); // Null byte to end QNAME
-        bytes.extend_from_slice(&self.qtype.to_be_bytes());
-        bytes.extend_from_slice(&self.qclass.to_be_bytes());
-        bytes
+    /// * `encoder` - The encoder the question is appended to.
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_name(&self.qname, true);
+        encoder.bytes.extend_from_slice(&self.qtype.to_be_bytes());
+        encoder.bytes.extend_from_slice(&self.qclass.to_be_bytes());
     }
 
     /// Deserializes a question from a byte slice.
@@ -390,17 +1142,14 @@ impl Question {
     ///
     /// # Returns
     ///
-    /// A `Result` containing a tuple with the question and the new offset in the byte slice if successful, or an `std::io::Error` if deserialization fails.
-    pub fn deserialize(data: &[u8], start_offset: usize) -> Result<(Self, usize), std::io::Error> {
+    /// A `Result` containing a tuple with the question and the new offset in the byte slice if successful, or a `DnsError` if deserialization fails.
+    pub fn deserialize(data: &[u8], start_offset: usize) -> Result<(Self, usize), DnsError> {
         let mut offset = start_offset;
         let (qname, new_offset) = Message::parse_qname(data, offset)?;
         offset = new_offset; // Update offset to position after QNAME
 
         if offset + 4 > data.len() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "Unexpected end of data",
-            ));
+            return Err(DnsError::Truncated);
         }
 
         let qtype = u16::from_be_bytes([data[offset], data[offset + 1]]);
@@ -419,10 +1168,508 @@ impl Question {
 }
 
 impl ResourceRecord {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        // Todo
-        return bytes;
+    /// Creates a record in the IN class.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The owner name in dotted form.
+    ///
+    /// * `ttl` - The number of seconds the record may be cached for.
+    ///
+    /// * `rdata` - The data of the record, which also gives its type.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new record, or a `DnsError` if the name is not valid or the type of
+    /// the data is not known, as for `RData::Unknown` and `RData::OPT`.
+    pub fn new(name: &str, ttl: u32, rdata: RData) -> Result<Self, DnsError> {
+        Ok(ResourceRecord {
+            name: Message::parse_name(name)?,
+            rtype: rdata.rtype().ok_or(DnsError::BadRdata)?,
+            rclass: 1, // IN
+            ttl,
+            rdata,
+        })
+    }
+
+    /// Gets the owner name of the record as a fully qualified name, e.g. `"example.com."`.
+    pub fn name(&self) -> String {
+        name_to_string(&self.name)
+    }
+
+    /// Gets the type of the record.
+    pub fn rtype(&self) -> RecordType {
+        self.rtype
+    }
+
+    /// Gets the number of seconds the record may be cached for.
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Gets the typed data of the record.
+    pub fn rdata(&self) -> &RData {
+        &self.rdata
+    }
+
+    /// Serializes a resource record into an encoder.
+    ///
+    /// # Arguments
+    ///
+    /// * `self` - The resource record to be serialized.
+    ///
+    /// * `encoder` - The encoder the resource record is appended to.
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_name(&self.name, true);
+        encoder.bytes.extend_from_slice(&self.rtype.to_u16().to_be_bytes());
+        encoder.bytes.extend_from_slice(&self.rclass.to_be_bytes());
+        encoder.bytes.extend_from_slice(&self.ttl.to_be_bytes());
+
+        // RDLENGTH is only known once the RDATA (and any compression in it) has been written.
+        let rdlength_offset = encoder.bytes.len();
+        encoder.bytes.extend_from_slice(&[0, 0]);
+        self.rdata.serialize(encoder);
+        let rdlength = (encoder.bytes.len() - rdlength_offset - 2) as u16;
+        encoder.bytes[rdlength_offset..rdlength_offset + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+
+    /// Deserializes a resource record from a byte slice.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The byte slice containing the resource record.
+    ///
+    /// * `start_offset` - The offset in the byte slice where the resource record starts.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with the resource record and the new offset in the byte slice if successful, or a `DnsError` if deserialization fails.
+    pub fn deserialize(data: &[u8], start_offset: usize) -> Result<(Self, usize), DnsError> {
+        let (name, mut offset) = Message::parse_qname(data, start_offset)?;
+
+        if offset + 10 > data.len() {
+            return Err(DnsError::Truncated);
+        }
+
+        let rtype = RecordType::from_u16(u16::from_be_bytes([data[offset], data[offset + 1]]));
+        let rclass = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+        let ttl = u32::from_be_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]);
+        let rdlength = u16::from_be_bytes([data[offset + 8], data[offset + 9]]);
+        offset += 10; // Move past type, class, ttl and rdlength
+
+        let end = offset + rdlength as usize;
+        if end > data.len() {
+            return Err(DnsError::Truncated);
+        }
+        let rdata = RData::deserialize(data, offset, end, rtype)?;
+
+        Ok((
+            ResourceRecord {
+                name,
+                rtype,
+                rclass,
+                ttl,
+                rdata,
+            },
+            end,
+        ))
+    }
+}
+
+impl RData {
+    /// Gets the record type the data belongs to, or `None` for the types without their own variant
+    /// and for OPT, which is never a record of its own.
+    fn rtype(&self) -> Option<RecordType> {
+        Some(match self {
+            RData::A(_) => RecordType::A,
+            RData::AAAA(_) => RecordType::AAAA,
+            RData::CNAME(_) => RecordType::CNAME,
+            RData::MX { .. } => RecordType::MX,
+            RData::NS(_) => RecordType::NS,
+            RData::PTR(_) => RecordType::PTR,
+            RData::SOA { .. } => RecordType::SOA,
+            RData::SRV { .. } => RecordType::SRV,
+            RData::TXT(_) => RecordType::TXT,
+            RData::OPT(_) | RData::Unknown(_) => return None,
+        })
+    }
+
+    /// Serializes RDATA into an encoder, without the RDLENGTH prefix.
+    ///
+    /// Names are only compressed for the types defined in RFC 1035, as required by RFC 3597.
+    ///
+    /// # Arguments
+    ///
+    /// * `self` - The RDATA to be serialized.
+    ///
+    /// * `encoder` - The encoder the RDATA is appended to.
+    fn serialize(&self, encoder: &mut Encoder) {
+        let bytes = &mut encoder.bytes;
+        match self {
+            RData::A(address) => bytes.extend_from_slice(&address.octets()),
+            RData::AAAA(address) => bytes.extend_from_slice(&address.octets()),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => {
+                encoder.write_name(name, true)
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                bytes.extend_from_slice(&preference.to_be_bytes());
+                encoder.write_name(exchange, true);
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                encoder.write_name(mname, true);
+                encoder.write_name(rname, true);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    encoder.bytes.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for value in [priority, weight, port] {
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                }
+                // RFC 2782 forbids compressing the target.
+                encoder.write_name(target, false);
+            }
+            RData::TXT(strings) => {
+                for string in strings {
+                    bytes.push(string.len() as u8); // Length octet
+                    bytes.extend_from_slice(string);
+                }
+            }
+            RData::OPT(options) => {
+                for option in options {
+                    bytes.extend_from_slice(&option.code.to_be_bytes());
+                    bytes.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(&option.data);
+                }
+            }
+            RData::Unknown(data) => bytes.extend_from_slice(data),
+        }
+    }
+
+    /// Deserializes RDATA of the given type from a byte slice.
+    ///
+    /// Domain names inside RDATA are parsed against the whole message, so `data` must be the
+    /// complete message rather than just the RDATA.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The byte slice containing the DNS message.
+    ///
+    /// * `start_offset` - The offset in the byte slice where the RDATA starts.
+    ///
+    /// * `end` - The offset in the byte slice where the RDATA ends, as given by RDLENGTH.
+    ///
+    /// * `rtype` - The type of the resource record the RDATA belongs to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the deserialized `RData` if successful, or a `DnsError` if deserialization fails.
+    fn deserialize(
+        data: &[u8],
+        start_offset: usize,
+        end: usize,
+        rtype: RecordType,
+    ) -> Result<Self, DnsError> {
+        let rdata = &data[start_offset..end];
+        let invalid = || DnsError::BadRdata;
+        // Parses a domain name that must lie entirely within the RDATA.
+        let parse_name = |offset: usize| Message::parse_qname(&data[..end], offset);
+        let read_u16 = |offset: usize| -> Result<u16, DnsError> {
+            match data[..end].get(offset..offset + 2) {
+                Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+                None => Err(invalid()),
+            }
+        };
+        let read_u32 = |offset: usize| -> Result<u32, DnsError> {
+            match data[..end].get(offset..offset + 4) {
+                Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                None => Err(invalid()),
+            }
+        };
+
+        let (rdata, offset) = match rtype {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| invalid())?;
+                (RData::A(Ipv4Addr::from(octets)), end)
+            }
+            RecordType::AAAA => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| invalid())?;
+                (RData::AAAA(Ipv6Addr::from(octets)), end)
+            }
+            RecordType::CNAME => {
+                let (name, offset) = parse_name(start_offset)?;
+                (RData::CNAME(name), offset)
+            }
+            RecordType::NS => {
+                let (name, offset) = parse_name(start_offset)?;
+                (RData::NS(name), offset)
+            }
+            RecordType::PTR => {
+                let (name, offset) = parse_name(start_offset)?;
+                (RData::PTR(name), offset)
+            }
+            RecordType::MX => {
+                let preference = read_u16(start_offset)?;
+                let (exchange, offset) = parse_name(start_offset + 2)?;
+                (
+                    RData::MX {
+                        preference,
+                        exchange,
+                    },
+                    offset,
+                )
+            }
+            RecordType::SOA => {
+                let (mname, offset) = parse_name(start_offset)?;
+                let (rname, offset) = parse_name(offset)?;
+                (
+                    RData::SOA {
+                        mname,
+                        rname,
+                        serial: read_u32(offset)?,
+                        refresh: read_u32(offset + 4)?,
+                        retry: read_u32(offset + 8)?,
+                        expire: read_u32(offset + 12)?,
+                        minimum: read_u32(offset + 16)?,
+                    },
+                    offset + 20,
+                )
+            }
+            RecordType::SRV => {
+                let priority = read_u16(start_offset)?;
+                let weight = read_u16(start_offset + 2)?;
+                let port = read_u16(start_offset + 4)?;
+                let (target, offset) = parse_name(start_offset + 6)?;
+                (
+                    RData::SRV {
+                        priority,
+                        weight,
+                        port,
+                        target,
+                    },
+                    offset,
+                )
+            }
+            RecordType::TXT => {
+                let mut strings = Vec::new();
+                let mut offset = 0;
+                while offset < rdata.len() {
+                    let length = rdata[offset] as usize;
+                    let string = rdata.get(offset + 1..offset + 1 + length).ok_or_else(invalid)?;
+                    strings.push(string.to_vec());
+                    offset += 1 + length;
+                }
+                (RData::TXT(strings), end)
+            }
+            RecordType::OPT => {
+                let mut options = Vec::new();
+                let mut offset = start_offset;
+                while offset < end {
+                    let code = read_u16(offset)?;
+                    let length = read_u16(offset + 2)? as usize;
+                    let option = data[..end].get(offset + 4..offset + 4 + length).ok_or_else(invalid)?;
+                    options.push(EdnsOption {
+                        code,
+                        data: option.to_vec(),
+                    });
+                    offset += 4 + length;
+                }
+                (RData::OPT(options), end)
+            }
+            RecordType::Unknown(_) => (RData::Unknown(rdata.to_vec()), end),
+        };
+
+        // Every byte announced by RDLENGTH must belong to the record.
+        if offset != end {
+            return Err(invalid());
+        }
+        Ok(rdata)
+    }
+}
+
+/// Formats RDATA in the presentation format of RFC 1035 5.1, or RFC 3597 5 for unknown types.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{}", address),
+            RData::AAAA(address) => write!(f, "{}", address),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => {
+                write!(f, "{}", name_to_string(name))
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, name_to_string(exchange)),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                name_to_string(mname),
+                name_to_string(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                priority,
+                weight,
+                port,
+                name_to_string(target)
+            ),
+            RData::TXT(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    f.write_str("\"")?;
+                    for &byte in string {
+                        match byte {
+                            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+                            0x20..=0x7E => write!(f, "{}", byte as char)?,
+                            _ => write!(f, "\\{:03}", byte)?,
+                        }
+                    }
+                    f.write_str("\"")?;
+                }
+                Ok(())
+            }
+            RData::OPT(_) | RData::Unknown(_) => {
+                let mut encoder = Encoder::new(false);
+                self.serialize(&mut encoder);
+                write!(f, "\\# {}", encoder.bytes.len())?;
+                if !encoder.bytes.is_empty() {
+                    f.write_str(" ")?;
+                }
+                for byte in &encoder.bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Formats a name as a fully qualified domain name, with the root as `"."`.
+fn name_to_string(labels: &[Vec<u8>]) -> String {
+    let mut name = labels_to_string(labels);
+    name.push('.');
+    name
+}
+
+/// Formats the labels of a name in presentation format, RFC 1035 5.1, without the trailing dot.
+/// Dots and backslashes within a label are escaped with a backslash, and octets that are not
+/// printable ASCII are written as `\DDD`, so that distinct names never format alike.
+fn labels_to_string(labels: &[Vec<u8>]) -> String {
+    let mut name = String::new();
+    for (index, label) in labels.iter().enumerate() {
+        if index > 0 {
+            name.push('.');
+        }
+        for &byte in label {
+            match byte {
+                b'.' | b'\\' => {
+                    name.push('\\');
+                    name.push(byte as char);
+                }
+                0x21..=0x7E => name.push(byte as char),
+                _ => name.push_str(&format!("\\{:03}", byte)),
+            }
+        }
+    }
+    name
+}
+
+impl RecordType {
+    /// Converts a `RecordType` to its corresponding numeric TYPE value.
+    pub fn to_u16(self) -> u16 {
+        match self {
+            Self::A => 1,
+            Self::NS => 2,
+            Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::PTR => 12,
+            Self::MX => 15,
+            Self::TXT => 16,
+            Self::AAAA => 28,
+            Self::SRV => 33,
+            Self::OPT => 41,
+            Self::Unknown(value) => value,
+        }
+    }
+
+    /// Converts a record type mnemonic such as `"AAAA"` to its `RecordType` variant.
+    /// Types without a dedicated variant can be given in the RFC 3597 form, e.g. `"TYPE65"`.
+    /// Returns `None` if the name is not recognised.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let rtype = match name.to_ascii_uppercase().as_str() {
+            "A" => Self::A,
+            "AAAA" => Self::AAAA,
+            "CNAME" => Self::CNAME,
+            "MX" => Self::MX,
+            "NS" => Self::NS,
+            "PTR" => Self::PTR,
+            "SOA" => Self::SOA,
+            "SRV" => Self::SRV,
+            "TXT" => Self::TXT,
+            "OPT" => Self::OPT,
+            other => Self::from_u16(other.strip_prefix("TYPE")?.parse().ok()?),
+        };
+        Some(rtype)
+    }
+
+    /// Converts a numeric TYPE value to its corresponding `RecordType` variant.
+    /// Values without a dedicated variant are kept as `RecordType::Unknown`.
+    pub fn from_u16(value: u16) -> Self {
+        match value {
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
+            other => Self::Unknown(other),
+        }
     }
 }
 
@@ -446,7 +1693,412 @@ impl RCode {
             7 => Some(Self::XRRSET),
             9 => Some(Self::NOTAUTH),
             10 => Some(Self::NOTZONE),
+            16 => Some(Self::BADVERS),
+            23 => Some(Self::BADCOOKIE),
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Edns {
+    /// Creates the EDNS(0) parameters we send in reply to a message carrying `request`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The EDNS(0) parameters of the request being answered.
+    ///
+    /// # Returns
+    ///
+    /// Parameters advertising our own UDP payload size and echoing the DO bit, RFC 3225 3.
+    pub fn new_response(request: &Edns) -> Self {
+        Edns::new(request.dnssec_ok)
+    }
+
+    /// Creates the EDNS(0) parameters we advertise.
+    ///
+    /// # Arguments
+    ///
+    /// * `dnssec_ok` - Whether DNSSEC records are wanted.
+    ///
+    /// # Returns
+    ///
+    /// Parameters advertising our own UDP payload size, without any options.
+    pub fn new(dnssec_ok: bool) -> Self {
+        Edns {
+            udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+            version: 0,
+            dnssec_ok,
+            z: 0,
+            options: Vec::new(),
+        }
+    }
+
+    /// Gets the largest UDP payload the sender is able to reassemble.
+    pub fn udp_payload_size(&self) -> u16 {
+        self.udp_payload_size
+    }
+
+    /// Gets whether the sender is able to accept DNSSEC records.
+    pub fn dnssec_ok(&self) -> bool {
+        self.dnssec_ok
+    }
+
+    /// Gets the EDNS version implemented by the sender. Only version 0 is defined.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Gets the options carried by the OPT pseudo-record.
+    pub fn options(&self) -> &[EdnsOption] {
+        &self.options
+    }
+
+    /// Builds the OPT pseudo-record carrying these parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `rcode` - The RCODE of the message, whose upper 8 bits are carried in the record.
+    ///
+    /// # Returns
+    ///
+    /// A resource record for the root name, with the parameters packed into its CLASS and TTL fields.
+    fn to_record(&self, rcode: RCode) -> ResourceRecord {
+        let flags = ((self.dnssec_ok as u16) << 15) | (self.z & 0x7FFF);
+        ResourceRecord {
+            name: Vec::new(),
+            rtype: RecordType::OPT,
+            rclass: self.udp_payload_size,
+            ttl: u32::from_be_bytes([
+                rcode.to_u8() >> 4,
+                self.version,
+                (flags >> 8) as u8,
+                flags as u8,
+            ]),
+            rdata: RData::OPT(self.options.clone()),
+        }
+    }
+
+    /// Extracts the parameters from an OPT pseudo-record.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The OPT pseudo-record from the additional section.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the parameters and the upper 8 bits of the message's RCODE if successful,
+    /// or a `DnsError` if the record is not a valid OPT pseudo-record.
+    fn from_record(record: ResourceRecord) -> Result<(Self, u8), DnsError> {
+        let RData::OPT(options) = record.rdata else {
+            return Err(DnsError::BadOpt);
+        };
+        if !record.name.is_empty() {
+            return Err(DnsError::BadOpt);
+        }
+        let [extended_rcode, version, flags_high, flags_low] = record.ttl.to_be_bytes();
+        let flags = u16::from_be_bytes([flags_high, flags_low]);
+        let edns = Edns {
+            udp_payload_size: record.rclass,
+            version,
+            dnssec_ok: flags & 0x8000 != 0,
+            z: flags & 0x7FFF,
+            options,
+        };
+        Ok((edns, extended_rcode))
+    }
+}
+
+impl EdnsOption {
+    /// Gets the code identifying the option.
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// Gets the option's data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Accumulates the wire form of a message, remembering where names were written so later
+/// occurrences can be replaced by compression pointers.
+struct Encoder {
+    /// The bytes of the message written so far.
+    bytes: Vec<u8>,
+    /// Offsets of previously written name suffixes, or `None` when compression is disabled.
+    names: Option<HashMap<Vec<Vec<u8>>, u16>>,
+}
+
+impl Encoder {
+    /// Creates an empty encoder.
+    ///
+    /// # Arguments
+    ///
+    /// * `compress` - Whether names should be compressed.
+    fn new(compress: bool) -> Self {
+        Encoder {
+            bytes: Vec::new(),
+            names: compress.then(HashMap::new),
+        }
+    }
+
+    /// Writes a domain name, replacing the longest suffix already written with a pointer.
+    ///
+    /// Suffixes are matched case-sensitively so that decoding the message yields exactly the
+    /// names that were encoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `labels` - The labels of the domain name.
+    ///
+    /// * `compress` - Whether this name may be compressed. Names that may not are still written
+    ///   in full and are not offered as pointer targets.
+    fn write_name(&mut self, labels: &[Vec<u8>], compress: bool) {
+        for (index, label) in labels.iter().enumerate() {
+            if compress {
+                if let Some(names) = self.names.as_mut() {
+                    let suffix = &labels[index..];
+                    if let Some(pointer) = names.get(suffix) {
+                        self.bytes.extend_from_slice(&(0xC000 | pointer).to_be_bytes());
+                        return;
+                    }
+                    if self.bytes.len() <= MAX_POINTER_OFFSET {
+                        names.insert(suffix.to_vec(), self.bytes.len() as u16);
+                    }
+                }
+            }
+            self.bytes.push(label.len() as u8); // Length octet
+            self.bytes.extend_from_slice(label); // Label octets
+        }
+        self.bytes.push(0); // Null byte to end the name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a message header with the given flags and section counts.
+    fn header(flags: u16, counts: [u16; 4]) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34];
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        for count in counts {
+            bytes.extend_from_slice(&count.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Encodes labels as an uncompressed name.
+    fn name(labels: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for label in labels {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label);
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    /// Builds a query for `qname`, type A, class IN.
+    fn query(qname: &[u8]) -> Vec<u8> {
+        let mut bytes = header(0x0100, [1, 0, 0, 0]);
+        bytes.extend_from_slice(qname);
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+        bytes
+    }
+
+    /// Builds a response to a query for `example.com` with `count` A records.
+    fn response(count: u16) -> Vec<u8> {
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+        let answers = (0..count)
+            .map(|i| {
+                let address = Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8);
+                ResourceRecord::new("example.com", 3600, RData::A(address)).unwrap()
+            })
+            .collect();
+        Message::new_response(&query, RCode::NOERROR, answers, Vec::new()).serialize()
+    }
+
+    #[test]
+    fn pointer_to_itself_is_rejected() {
+        let data = query(&[0xC0, 0x0C]);
+        assert_eq!(Message::deserialize(&data), Err(DnsError::PointerLoop));
+    }
+
+    #[test]
+    fn forward_pointer_is_rejected() {
+        let mut data = query(&[0xC0, 0x12]);
+        data.extend_from_slice(&name(&[b"example"]));
+        assert_eq!(Message::deserialize(&data), Err(DnsError::PointerLoop));
+    }
+
+    #[test]
+    fn pointer_chains_are_limited() {
+        // Every question after the first points at the name of the one before it, so the last
+        // question takes one hop per question before it.
+        let chain = |questions: u16| {
+            let mut data = header(0x0100, [questions, 0, 0, 0]);
+            data.extend_from_slice(&[0, 0, 1, 0, 1]);
+            for i in 1..questions as usize {
+                let previous = if i == 1 { 12 } else { 17 + 6 * (i - 2) };
+                data.extend_from_slice(&(0xC000 | previous as u16).to_be_bytes());
+                data.extend_from_slice(&[0, 1, 0, 1]);
+            }
+            data
+        };
+        assert!(Message::deserialize(&chain(MAX_POINTER_HOPS as u16 + 1)).is_ok());
+        assert_eq!(
+            Message::deserialize(&chain(MAX_POINTER_HOPS as u16 + 2)),
+            Err(DnsError::PointerLoop)
+        );
+    }
+
+    #[test]
+    fn labels_are_limited_to_63_octets() {
+        let longest = [b'a'; MAX_LABEL_LENGTH];
+        assert!(Message::deserialize(&query(&name(&[&longest]))).is_ok());
+        assert_eq!(
+            Message::deserialize(&query(&name(&[&[b'a'; MAX_LABEL_LENGTH + 1]]))),
+            Err(DnsError::LabelTooLong)
+        );
+
+        let text = String::from_utf8(longest.to_vec()).unwrap();
+        assert!(Message::new_simple_query(&text, RecordType::A, 0).is_ok());
+        assert_eq!(
+            Message::new_simple_query(&format!("{}a", text), RecordType::A, 0),
+            Err(DnsError::LabelTooLong)
+        );
+    }
+
+    #[test]
+    fn names_are_limited_to_255_octets() {
+        // Three full labels and one of 61 octets take 255 octets with the root.
+        let full = [b'a'; 63];
+        let longest = name(&[&full, &full, &full, &[b'b'; 61]]);
+        assert_eq!(longest.len(), MAX_NAME_LENGTH);
+        assert!(Message::deserialize(&query(&longest)).is_ok());
+        assert_eq!(
+            Message::deserialize(&query(&name(&[&full, &full, &full, &[b'b'; 62]]))),
+            Err(DnsError::NameTooLong)
+        );
+
+        let label = String::from_utf8(full.to_vec()).unwrap();
+        let text = format!("{0}.{0}.{0}.{1}", label, "b".repeat(61));
+        assert!(Message::new_simple_query(&text, RecordType::A, 0).is_ok());
+        assert_eq!(
+            Message::new_simple_query(&format!("{}b", text), RecordType::A, 0),
+            Err(DnsError::NameTooLong)
+        );
+    }
+
+    #[test]
+    fn truncated_rdata_is_rejected() {
+        let mut data = response(1);
+        data.truncate(data.len() - 2);
+        assert_eq!(Message::deserialize(&data), Err(DnsError::Truncated));
+
+        // An A record whose RDLENGTH does not fit an IPv4 address.
+        let mut data = response(1);
+        let rdlength = data.len() - 6;
+        data[rdlength + 1] = 3;
+        data.pop();
+        assert_eq!(Message::deserialize(&data), Err(DnsError::BadRdata));
+    }
+
+    #[test]
+    fn opt_is_lifted_out_of_the_additional_section() {
+        let opt = [0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0];
+        let mut data = query(&name(&[b"example", b"com"]));
+        data[11] = 1; // ARCOUNT
+        data.extend_from_slice(&opt);
+
+        let message = Message::deserialize(&data).unwrap();
+        let edns = message.edns().unwrap();
+        assert_eq!(edns.udp_payload_size(), 4096);
+        assert!(edns.dnssec_ok());
+        assert!(message.extra.is_empty());
+        assert_eq!(message.serialize(), data);
+
+        let mut twice = data.clone();
+        twice[11] = 2;
+        twice.extend_from_slice(&opt);
+        assert_eq!(Message::deserialize(&twice), Err(DnsError::BadOpt));
+
+        let mut named = query(&name(&[b"example", b"com"]));
+        named[11] = 1;
+        named.extend_from_slice(&[0xC0, 0x0C]);
+        named.extend_from_slice(&opt[1..]);
+        assert_eq!(Message::deserialize(&named), Err(DnsError::BadOpt));
+    }
+
+    #[test]
+    fn extended_rcodes_are_combined_with_the_opt_record() {
+        // BADVERS (16) is 1 in the upper 8 bits and 0 in the header.
+        let mut data = header(0x8180, [1, 0, 0, 1]);
+        data.extend_from_slice(&name(&[b"example", b"com"]));
+        data.extend_from_slice(&[0, 1, 0, 1]);
+        data.extend_from_slice(&[0, 0, 41, 0x10, 0, 1, 0, 0, 0, 0, 0]);
+
+        let message = Message::deserialize(&data).unwrap();
+        assert_eq!(message.rcode(), RCode::BADVERS);
+        assert_eq!(message.serialize(), data);
+
+        // BADCOOKIE (23) is 1 in the upper 8 bits and 7 in the header.
+        data[3] |= 7;
+        let message = Message::deserialize(&data).unwrap();
+        assert_eq!(message.rcode(), RCode::BADCOOKIE);
+
+        let extended_rcode = data.len() - 6;
+        data[extended_rcode] = 2;
+        assert_eq!(Message::deserialize(&data), Err(DnsError::BadRcode(39)));
+    }
+
+    #[test]
+    fn oversized_responses_are_truncated() {
+        let small = Message::deserialize(&response(2)).unwrap();
+        assert_eq!(small.serialize_truncated(512), small.serialize());
+
+        let large = Message::deserialize(&response(100)).unwrap();
+        let bytes = large.serialize_truncated(512);
+        assert!(bytes.len() <= 512);
+        let truncated = Message::deserialize(&bytes).unwrap();
+        assert!(truncated.is_truncated());
+        assert!(truncated.answers().is_empty());
+        assert_eq!(truncated.qname_to_string(), "example.com");
+    }
+
+    #[test]
+    fn labels_may_hold_any_octet() {
+        let data = query(&name(&[b"a\xFFb", b"c.d", b"e\\f"]));
+        let message = Message::deserialize(&data).unwrap();
+        assert_eq!(message.qname_to_string(), "a\\255b.c\\.d.e\\\\f");
+        assert_eq!(message.serialize(), data);
+
+        let parsed = Message::new_simple_query("a\\255b.c\\.d.e\\\\f.", RecordType::A, 0x1234);
+        assert_eq!(parsed.unwrap().question, message.question);
+        assert_eq!(
+            Message::new_simple_query("a\\256", RecordType::A, 0),
+            Err(DnsError::BadLabel)
+        );
+        assert_eq!(
+            Message::new_simple_query("a..b", RecordType::A, 0),
+            Err(DnsError::BadLabel)
+        );
+    }
+
+    #[test]
+    fn malformed_queries_get_a_format_error() {
+        let mut data = query(&name(&[b"example", b"com"]));
+        data.truncate(data.len() - 2);
+        assert!(Message::deserialize(&data).is_err());
+
+        let response = Message::new_format_error_response(&data).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.rcode(), RCode::FORMERR);
+        assert!(response.is_recursion_desired());
+
+        // Responses and data shorter than a header are left unanswered.
+        assert!(Message::new_format_error_response(&response.serialize()).is_none());
+        assert!(Message::new_format_error_response(&data[..11]).is_none());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod blocklist;
+pub mod cache;
+pub mod dns;
+pub mod listeners;
+pub mod requests;
+pub mod resolver;
+pub mod settings;
+pub mod tls_config;
+pub mod upstreams;
@@ -1,70 +1,254 @@
 use crate::dns::Message;
-use crate::requests::Request;
-use crate::settings::ListenersSettings;
+use crate::requests::{Request, StreamWriter};
+use crate::settings::{BindSettings, CertificateSettings, ConnectionSettings, ListenersSettings};
+use crate::tls_config::{self, ALPN_DOQ, ALPN_DOT, ALPN_H2, ALPN_HTTP1};
+use rustls::ServerConfig;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::net::{tcp, TcpListener, UdpSocket};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 
+mod https;
+mod quic;
+#[cfg(test)]
+pub(crate) mod testing;
+
+/// The size of the buffer UDP datagrams are received into. EDNS clients may send queries larger
+/// than 512 bytes, and anything that does not fit would be silently cut off by the socket.
+const UDP_RECEIVE_BUFFER_SIZE: usize = 4096;
+
+/// The number of connections the system queues for a stream listener until they are accepted.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// The number of pipelined queries from one TCP or TLS connection that are resolved at once.
+/// Further queries are left unread until a response has been written.
+const MAX_PIPELINED_QUERIES: usize = 32;
+
+/// The sockets of every enabled listener, one per bind address. A disabled listener has none.
 pub struct Listeners {
-    udp_listener: Option<Arc<UdpSocket>>,
-    tcp_listener: Option<Arc<TcpListener>>,
+    udp_listeners: Vec<Arc<UdpSocket>>,
+    tcp_listeners: Vec<StreamListener>,
+    tls_listeners: Vec<StreamListener>,
+    https_listeners: Vec<StreamListener>,
+    /// The DNS over QUIC endpoints and the connection limit they share.
+    quic_listeners: Vec<(quinn::Endpoint, Arc<Semaphore>)>,
+}
+
+/// A listener for DNS carried over TCP connections.
+#[derive(Clone)]
+struct StreamListener {
+    listener: Arc<TcpListener>,
+    protocol: StreamProtocol,
+    idle_timeout: Duration,
+    /// Shared by all addresses of the same listener, so the limit applies to them together.
+    connections: Arc<Semaphore>,
+}
+
+/// How DNS messages are carried on the connections of a stream listener.
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+enum StreamProtocol {
+    /// Length-prefixed messages, RFC 7766.
+    TCP,
+    /// Length-prefixed messages over TLS, RFC 7858.
+    TLS(TlsAcceptor),
+    /// HTTP requests over TLS, RFC 8484, optionally also in the JSON format.
+    HTTPS { acceptor: TlsAcceptor, json: bool },
+}
+
+impl StreamListener {
+    /// Binds a listener to each of its addresses.
+    async fn bind(
+        settings: &BindSettings,
+        limits: &ConnectionSettings,
+        protocol: StreamProtocol,
+    ) -> io::Result<Vec<Self>> {
+        let connections = Arc::new(Semaphore::new(limits.max_connections));
+        bind_sockets(settings, protocol.name(), Type::STREAM, 1)
+            .await?
+            .into_iter()
+            .map(|socket| {
+                socket.listen(LISTEN_BACKLOG)?;
+                Ok(Self {
+                    listener: Arc::new(TcpListener::from_std(socket.into())?),
+                    protocol: protocol.clone(),
+                    idle_timeout: Duration::from_secs(limits.idle_timeout),
+                    connections: connections.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl StreamProtocol {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamProtocol::TCP => "TCP",
+            StreamProtocol::TLS(_) => "TLS",
+            StreamProtocol::HTTPS { .. } => "HTTPS",
+        }
+    }
 }
 
 impl Listeners {
     pub async fn new(settings: &ListenersSettings) -> io::Result<Self> {
-        let udp_listener = if settings.udp.enabled {
-            let address = format!("{}:{}", settings.udp.address, settings.udp.port);
-            Some(Arc::new(UdpSocket::bind(&address).await?))
+        let udp_listeners = if settings.udp.bind.enabled {
+            // Each socket gets its own receive loop, so busy servers can use several cores.
+            let copies = settings.udp.sockets.max(1);
+            bind_sockets(&settings.udp.bind, "UDP", Type::DGRAM, copies)
+                .await?
+                .into_iter()
+                .map(|socket| Ok(Arc::new(UdpSocket::from_std(socket.into())?)))
+                .collect::<io::Result<_>>()?
         } else {
-            None
+            Vec::new()
         };
 
-        let tcp_listener = if settings.tcp.enabled {
-            let address = format!("{}:{}", settings.tcp.address, settings.tcp.port);
-            Some(Arc::new(TcpListener::bind(&address).await?))
+        let tcp = &settings.tcp;
+        let tcp_listeners = if tcp.bind.enabled {
+            StreamListener::bind(&tcp.bind, &tcp.connections, StreamProtocol::TCP).await?
         } else {
-            None
+            Vec::new()
+        };
+
+        let tls_listeners = match settings.tls.as_ref().filter(|tls| tls.bind.enabled) {
+            Some(tls) => {
+                let config = Self::tls_config(&tls.certificate, "TLS", &[ALPN_DOT])?;
+                let acceptor = TlsAcceptor::from(Arc::new(config));
+                let protocol = StreamProtocol::TLS(acceptor);
+                StreamListener::bind(&tls.bind, &tls.connections, protocol).await?
+            }
+            None => Vec::new(),
+        };
+
+        let https_listeners = match settings.https.as_ref().filter(|https| https.bind.enabled) {
+            Some(https) => {
+                // HTTP/2 is preferred, RFC 8484 5.2.
+                let alpn = [ALPN_H2, ALPN_HTTP1];
+                let config = Self::tls_config(&https.certificate, "HTTPS", &alpn)?;
+                let acceptor = TlsAcceptor::from(Arc::new(config));
+                let protocol = StreamProtocol::HTTPS {
+                    acceptor,
+                    json: https.json,
+                };
+                StreamListener::bind(&https.bind, &https.connections, protocol).await?
+            }
+            None => Vec::new(),
+        };
+
+        let quic_listeners = match settings.quic.as_ref().filter(|quic| quic.bind.enabled) {
+            Some(quic) => {
+                let config = Self::tls_config(&quic.certificate, "QUIC", &[ALPN_DOQ])?;
+                let idle_timeout = Duration::from_secs(quic.connections.idle_timeout);
+                let config = quic::server_config(config, idle_timeout)?;
+                let connections = Arc::new(Semaphore::new(quic.connections.max_connections));
+                bind_sockets(&quic.bind, "QUIC", Type::DGRAM, 1)
+                    .await?
+                    .into_iter()
+                    .map(|socket| {
+                        Ok((
+                            quic::bind(socket.into(), config.clone())?,
+                            connections.clone(),
+                        ))
+                    })
+                    .collect::<io::Result<_>>()?
+            }
+            None => Vec::new(),
         };
 
         Ok(Self {
-            udp_listener,
-            tcp_listener,
+            udp_listeners,
+            tcp_listeners,
+            tls_listeners,
+            https_listeners,
+            quic_listeners,
         })
     }
 
+    /// Loads the certificate and key of an encrypted listener.
+    fn tls_config(
+        settings: &CertificateSettings,
+        protocol: &str,
+        alpn: &[&[u8]],
+    ) -> io::Result<ServerConfig> {
+        let (Some(certificate_file), Some(key_file)) =
+            (&settings.certificate_file, &settings.key_file)
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The {} listener needs a certificate_file and a key_file",
+                    protocol
+                ),
+            ));
+        };
+        tls_config::server_config(certificate_file, key_file, alpn)
+    }
+
     pub async fn listen(&self) -> io::Result<Receiver<Request>> {
         let (tx, rx): (Sender<Request>, Receiver<Request>) = mpsc::channel(100);
 
-        if let Some(udp_socket) = self.udp_listener.as_ref() {
-            let udp_sender = tx.clone();
-            tokio::spawn(Self::handle_udp(udp_socket.clone(), udp_sender));
-            println!(
-                "Listening for UDP requests on {}",
-                udp_socket.local_addr().unwrap()
-            ); // Consider handling the Result properly
-        } else {
+        for udp_socket in &self.udp_listeners {
+            tokio::spawn(Self::handle_udp(udp_socket.clone(), tx.clone()));
+            println!("Listening for UDP requests on {}", udp_socket.local_addr()?);
+        }
+        if self.udp_listeners.is_empty() {
             println!("UDP listener is disabled");
         }
 
-        if let Some(tcp_listener) = self.tcp_listener.as_ref() {
-            let tcp_sender = tx.clone();
-            tokio::spawn(Self::handle_tcp(tcp_listener.clone(), tcp_sender));
-            println!(
-                "Listening for TCP requests on {}",
-                tcp_listener.local_addr().unwrap()
-            ); // Consider handling the Result properly
-        } else {
-            println!("TCP listener is disabled");
+        let stream_listeners = [
+            ("TCP", &self.tcp_listeners),
+            ("TLS", &self.tls_listeners),
+            ("HTTPS", &self.https_listeners),
+        ];
+        for (protocol, stream_listeners) in stream_listeners {
+            for stream_listener in stream_listeners {
+                tokio::spawn(Self::handle_stream(stream_listener.clone(), tx.clone()));
+                println!(
+                    "Listening for {} requests on {}",
+                    protocol,
+                    stream_listener.listener.local_addr()?
+                );
+            }
+            if stream_listeners.is_empty() {
+                println!("{} listener is disabled", protocol);
+            }
+        }
+
+        for (endpoint, connections) in &self.quic_listeners {
+            tokio::spawn(quic::handle_quic(
+                endpoint.clone(),
+                connections.clone(),
+                tx.clone(),
+            ));
+            println!("Listening for QUIC requests on {}", endpoint.local_addr()?);
+        }
+        if self.quic_listeners.is_empty() {
+            println!("QUIC listener is disabled");
         }
 
         Ok(rx)
     }
 
     async fn handle_udp(socket: Arc<UdpSocket>, sender: Sender<Request>) -> io::Result<()> {
-        let mut buf = [0u8; 512];
+        let mut buf = vec![0u8; UDP_RECEIVE_BUFFER_SIZE];
         loop {
-            let (size, addr) = socket.recv_from(&mut buf).await?;
+            // A failed receive only concerns one datagram, so keep serving the rest.
+            let (size, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive UDP datagram: {}", e);
+                    continue;
+                }
+            };
             match Message::deserialize(&buf[..size]) {
                 Ok(msg) => {
                     let request = Request::new_udp(socket.clone(), addr, msg);
@@ -72,29 +256,294 @@ impl Listeners {
                         eprintln!("Failed to send UDP request through channel");
                     }
                 }
-                Err(e) => eprintln!("Failed to deserialize message: {}", e),
+                Err(e) => {
+                    eprintln!("Failed to deserialize message: {}", e);
+                    if let Some(response) = Message::new_format_error_response(&buf[..size]) {
+                        if let Err(e) = socket.send_to(&response.serialize(), addr).await {
+                            eprintln!("Failed to send UDP response: {}", e);
+                        }
+                    }
+                }
             }
         }
     }
 
-
-    async fn handle_tcp(listener: Arc<TcpListener>, sender: Sender<Request>) -> io::Result<()> {
+    /// Accepts connections on a stream listener and serves each on its own task.
+    async fn handle_stream(listener: StreamListener, sender: Sender<Request>) -> io::Result<()> {
+        let protocol = listener.protocol.name();
         loop {
-            /*let (stream, addr) = listener.accept().await?;
+            let (stream, addr) = match listener.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept {} connection: {}", protocol, e);
+                    continue;
+                }
+            };
+
+            // Connections over the limit are closed straight away rather than left waiting.
+            let Ok(permit) = listener.connections.clone().try_acquire_owned() else {
+                eprintln!(
+                    "Refusing {} connection from {}: connection limit reached",
+                    protocol, addr
+                );
+                continue;
+            };
+
             let sender = sender.clone();
+            let stream_protocol = listener.protocol.clone();
+            let idle_timeout = listener.idle_timeout;
             tokio::spawn(async move {
-                let mut buf = [0u8; 512];
-                let size = stream.read(&mut buf).await.unwrap();
-                match Message::deserialize(&buf[..size]) {
-                    Ok(msg) => {
-                        let request = Request::new_tcp(Arc::new(tokio::sync::Mutex::new(stream)), msg);
-                        if sender.send(request).await.is_err() {
-                            eprintln!("Failed to send TCP request through channel");
-                        }
+                if let Err(e) =
+                    Self::serve_connection(stream, stream_protocol, idle_timeout, &sender).await
+                {
+                    eprintln!("{} connection from {} failed: {}", protocol, addr, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Completes the TLS handshake, if any, and then reads requests from the connection.
+    async fn serve_connection(
+        stream: TcpStream,
+        protocol: StreamProtocol,
+        idle_timeout: Duration,
+        sender: &Sender<Request>,
+    ) -> io::Result<()> {
+        match protocol {
+            StreamProtocol::TCP => {
+                let (reader, writer) = stream.into_split();
+                let writer = shared(writer, idle_timeout);
+                Self::read_tcp_messages(reader, idle_timeout, writer, sender).await
+            }
+            StreamProtocol::TLS(acceptor) => {
+                let stream = Self::accept_tls(stream, &acceptor, idle_timeout).await?;
+                let (reader, writer) = tokio::io::split(stream);
+                let writer = shared(writer, idle_timeout);
+                Self::read_tcp_messages(reader, idle_timeout, writer, sender).await
+            }
+            StreamProtocol::HTTPS { acceptor, json } => {
+                let stream = Self::accept_tls(stream, &acceptor, idle_timeout).await?;
+                https::serve_connection(stream, idle_timeout, json, sender).await
+            }
+        }
+    }
+
+    async fn accept_tls(
+        stream: TcpStream,
+        acceptor: &TlsAcceptor,
+        idle_timeout: Duration,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        // A client that never finishes the handshake is treated like an idle one.
+        match timeout(idle_timeout, acceptor.accept(stream)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out during TLS handshake",
+            )),
+        }
+    }
+
+    /// Reads length-prefixed DNS messages (RFC 7766 8) from a connection until it is closed or idle.
+    ///
+    /// Each message is queued as its own request as soon as it is read, so pipelined queries are
+    /// resolved concurrently and their responses are written back in whatever order they complete.
+    /// Once `MAX_PIPELINED_QUERIES` are in flight, the next message is only read after a response
+    /// has been written.
+    async fn read_tcp_messages(
+        mut reader: impl AsyncRead + Unpin,
+        idle_timeout: Duration,
+        writer: Arc<StreamWriter>,
+        sender: &Sender<Request>,
+    ) -> io::Result<()> {
+        loop {
+            let mut length = [0u8; 2];
+            match timeout(idle_timeout, reader.read_exact(&mut length)).await {
+                Ok(Ok(_)) => {}
+                // A clean close between messages, or an idle client, ends the connection.
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            }
+
+            let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+            match timeout(idle_timeout, reader.read_exact(&mut buf)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Timed out reading message",
+                    ))
+                }
+            };
+
+            match Message::deserialize(&buf) {
+                Ok(msg) => {
+                    // The writer gives up on clients that stop reading their responses.
+                    let Some(in_flight) = writer.reserve().await else {
+                        return Ok(());
+                    };
+                    if sender
+                        .send(Request::new_tcp(writer.clone(), in_flight, msg))
+                        .await
+                        .is_err()
+                    {
+                        eprintln!("Failed to send TCP request through channel");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to deserialize message: {}", e);
+                    // The frame was read whole, so the connection stays usable for the next one.
+                    if let Some(response) = Message::new_format_error_response(&buf) {
+                        writer.write(&response.serialize_framed()).await?;
                     }
-                    Err(e) => eprintln!("Failed to deserialize message: {}", e),
                 }
-            });*/
+            }
+        }
+    }
+}
+
+/// Wraps the write half of a connection so that every request read from it can respond.
+/// A response that cannot be written for as long as the connection may stay idle gives it up.
+fn shared(
+    writer: impl AsyncWrite + Send + Unpin + 'static,
+    idle_timeout: Duration,
+) -> Arc<StreamWriter> {
+    Arc::new(StreamWriter::new(
+        writer,
+        idle_timeout,
+        MAX_PIPELINED_QUERIES,
+    ))
+}
+
+/// Opens a socket on every address of a listener, with its socket options applied.
+///
+/// # Arguments
+///
+/// * `settings` - The listener's addresses, port and socket options.
+///
+/// * `protocol` - The name of the listener, for error messages.
+///
+/// * `socket_type` - `Type::DGRAM` for UDP sockets or `Type::STREAM` for TCP ones.
+///
+/// * `copies` - The number of sockets to open on each address. More than one share the address
+///   with `SO_REUSEPORT`, and the kernel spreads incoming traffic across them.
+///
+/// # Returns
+///
+/// The bound, non-blocking sockets, `copies` per address, or the first error naming the address.
+async fn bind_sockets(
+    settings: &BindSettings,
+    protocol: &str,
+    socket_type: Type,
+    copies: usize,
+) -> io::Result<Vec<Socket>> {
+    if settings.addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The {} listener has no addresses", protocol),
+        ));
+    }
+
+    let mut addresses = Vec::new();
+    for address in &settings.addresses {
+        match address.parse::<IpAddr>() {
+            Ok(ip) => addresses.push(SocketAddr::new(ip, settings.port)),
+            Err(_) => {
+                addresses.extend(tokio::net::lookup_host((address.as_str(), settings.port)).await?)
+            }
+        }
+    }
+
+    let mut sockets = Vec::new();
+    for address in addresses {
+        for _ in 0..copies {
+            let socket = bind_socket(address, settings, socket_type, copies > 1).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to bind {} listener to {}: {}", protocol, address, e),
+                )
+            })?;
+            sockets.push(socket);
+        }
+    }
+    Ok(sockets)
+}
+
+fn bind_socket(
+    address: SocketAddr,
+    settings: &BindSettings,
+    socket_type: Type,
+    reuse_port: bool,
+) -> io::Result<Socket> {
+    let socket_protocol = if socket_type == Type::STREAM {
+        Protocol::TCP
+    } else {
+        Protocol::UDP
+    };
+    let socket = Socket::new(
+        Domain::for_address(address),
+        socket_type,
+        Some(socket_protocol),
+    )?;
+    if address.is_ipv6() {
+        if let Some(v6only) = settings.v6only {
+            socket.set_only_v6(v6only)?;
         }
     }
+    if let Some(interface) = &settings.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+    if socket_type == Type::STREAM {
+        // Lets a restarted server bind while connections of the previous one are in TIME_WAIT.
+        socket.set_reuse_address(true)?;
+    }
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_to_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_to_interface(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot bind to interface {} on this platform", interface),
+    ))
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin",
+        target_os = "nuttx"
+    ))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin",
+        target_os = "nuttx"
+    ))
+)))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Cannot open several sockets on one address on this platform",
+    ))
 }
@@ -0,0 +1,319 @@
+use crate::dns::{Edns, Message, RecordType, ResourceRecord};
+use crate::requests::Request;
+use crate::tls_config::{DNS_JSON, DNS_MESSAGE};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Response, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio_rustls::server::TlsStream;
+
+/// The path queries are served at, as used by the well-known DoH deployments.
+const DOH_PATH: &str = "/dns-query";
+
+/// How the client asked for the response to be encoded.
+enum Format {
+    Wire,
+    Json,
+}
+
+/// Serves HTTP/1.1 or HTTP/2, as negotiated, on an established TLS connection.
+///
+/// # Arguments
+///
+/// * `stream` - The connection, after the TLS handshake.
+///
+/// * `idle_timeout` - How long an HTTP/1.1 client may take to send the next request.
+///   HTTP/2 connections are instead pinged at this interval and closed if the client is gone.
+///
+/// * `json` - Whether to answer JSON API requests as well as RFC 8484 ones.
+///
+/// * `sender` - The queue that requests are passed to the resolver on.
+pub(super) async fn serve_connection(
+    stream: TlsStream<TcpStream>,
+    idle_timeout: Duration,
+    json: bool,
+    sender: &Sender<Request>,
+) -> io::Result<()> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(idle_timeout);
+
+    let sender = sender.clone();
+    let service = service_fn(move |http_request| {
+        let sender = sender.clone();
+        async move {
+            let response = match answer(http_request, &sender, json).await {
+                Ok(response) => response,
+                Err(status) => error_response(status),
+            };
+            Ok::<_, Infallible>(response)
+        }
+    });
+    builder
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+        .map_err(io::Error::other)
+}
+
+/// Decodes the query carried by an HTTP request, queues it and encodes the resolver's response.
+async fn answer(
+    http_request: hyper::Request<Incoming>,
+    sender: &Sender<Request>,
+    json: bool,
+) -> Result<Response<Full<Bytes>>, StatusCode> {
+    if http_request.uri().path() != DOH_PATH {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let (message, format) = match *http_request.method() {
+        Method::GET => parse_get(http_request.uri(), json)?,
+        Method::POST => (parse_post(http_request).await?, Format::Wire),
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let (request, receiver) = Request::new_https(message);
+    if sender.send(request).await.is_err() {
+        eprintln!("Failed to send HTTPS request through channel");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    // The resolver drops requests that it decides not to answer.
+    let response = receiver.await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+
+    Ok(match format {
+        Format::Wire => wire_response(&response),
+        Format::Json => json_response(&response),
+    })
+}
+
+/// Reads the query from the `dns` parameter, RFC 8484 4.1, or from the `name` and `type`
+/// parameters of the JSON API.
+fn parse_get(uri: &Uri, json: bool) -> Result<(Message, Format), StatusCode> {
+    let params: HashMap<String, String> =
+        form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+
+    if let Some(dns) = params.get("dns") {
+        // The parameter is base64url without padding, RFC 8484 6, but padding is tolerated.
+        let query = URL_SAFE_NO_PAD
+            .decode(dns.trim_end_matches('='))
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let message = Message::deserialize(&query).map_err(|_| StatusCode::BAD_REQUEST)?;
+        return Ok((message, Format::Wire));
+    }
+
+    match params.get("name") {
+        Some(name) if json => Ok((json_query(name, &params)?, Format::Json)),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Reads the query from the body of a POST request, RFC 8484 4.1.
+async fn parse_post(http_request: hyper::Request<Incoming>) -> Result<Message, StatusCode> {
+    let content_type = http_request.headers().get(CONTENT_TYPE);
+    if content_type.map(|v| v.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let body = Limited::new(http_request.into_body(), u16::MAX as usize)
+        .collect()
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?
+        .to_bytes();
+    Message::deserialize(&body).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Builds the query for a JSON API request. The type may be given as a mnemonic or a number
+/// and defaults to A, and the `do` and `cd` flags may be given as `1` or `true`.
+fn json_query(name: &str, params: &HashMap<String, String>) -> Result<Message, StatusCode> {
+    let rtype = match params.get("type") {
+        Some(rtype) => RecordType::from_name(rtype)
+            .or_else(|| rtype.parse().ok().map(RecordType::from_u16))
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => RecordType::A,
+    };
+    let flag = |key| params.get(key).is_some_and(|v| v == "1" || v == "true");
+
+    let mut query =
+        Message::new_simple_query(name, rtype, 0).map_err(|_| StatusCode::BAD_REQUEST)?;
+    query.set_checking_disabled(flag("cd"));
+    query.set_edns(Some(Edns::new(flag("do"))));
+    Ok(query)
+}
+
+fn wire_response(response: &Message) -> Response<Full<Bytes>> {
+    let body = response.serialize_truncated(u16::MAX as usize);
+    let mut builder = Response::builder().header(CONTENT_TYPE, DNS_MESSAGE);
+    // HTTP caches must not keep the response for longer than its records, RFC 8484 5.1.
+    if let Some(ttl) = response.min_ttl() {
+        builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    builder
+        .body(Full::new(Bytes::from(body)))
+        .expect("response headers are valid")
+}
+
+fn json_response(response: &Message) -> Response<Full<Bytes>> {
+    let records = |records: &[ResourceRecord]| -> Value {
+        records
+            .iter()
+            .map(|record| {
+                json!({
+                    "name": record.name(),
+                    "type": record.rtype().to_u16(),
+                    "TTL": record.ttl(),
+                    "data": record.rdata().to_string(),
+                })
+            })
+            .collect()
+    };
+
+    let mut body = json!({
+        "Status": response.rcode().to_u8(),
+        "TC": response.is_truncated(),
+        "RD": response.is_recursion_desired(),
+        "RA": response.is_recursion_available(),
+        "AD": response.is_authentic_data(),
+        "CD": response.is_checking_disabled(),
+    });
+    if let Some(qtype) = response.qtype() {
+        body["Question"] = json!([{
+            "name": format!("{}.", response.qname_to_string()),
+            "type": qtype.to_u16(),
+        }]);
+    }
+    if !response.answers().is_empty() {
+        body["Answer"] = records(response.answers());
+    }
+    if !response.authorities().is_empty() {
+        body["Authority"] = records(response.authorities());
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, DNS_JSON)
+        .body(Full::new(Bytes::from(body.to_string())))
+        .expect("response headers are valid")
+}
+
+fn error_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listeners::testing::{self, CERTIFICATE_FILE};
+    use crate::tls_config::{self, ALPN_H2};
+    use hyper_util::rt::TokioExecutor;
+    use rustls::pki_types::ServerName;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio_rustls::TlsConnector;
+
+    /// Sends a request to a listener over HTTP/2 and reads the whole response.
+    async fn send(address: SocketAddr, request: hyper::Request<Full<Bytes>>) -> Response<Bytes> {
+        let config = tls_config::client_config(Some(CERTIFICATE_FILE), &[], &[ALPN_H2]).unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let (mut sender, driver) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(driver);
+
+        let (parts, body) = sender.send_request(request).await.unwrap().into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn get(uri: &str) -> hyper::Request<Full<Bytes>> {
+        hyper::Request::get(uri).body(Full::default()).unwrap()
+    }
+
+    fn post(content_type: &str, body: Vec<u8>) -> hyper::Request<Full<Bytes>> {
+        hyper::Request::post("https://localhost/dns-query")
+            .header(CONTENT_TYPE, content_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn wire_format_queries_are_answered_with_get_and_post() {
+        let (addresses, queue) = testing::listen().await;
+        testing::answer_all(queue);
+        let query = Message::new_simple_query("example.com", RecordType::A, 0).unwrap();
+        let encoded = URL_SAFE_NO_PAD.encode(query.serialize());
+
+        let uri = format!("https://localhost/dns-query?dns={}", encoded);
+        for request in [get(&uri), post(DNS_MESSAGE, query.serialize())] {
+            let response = send(addresses.https, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_TYPE], DNS_MESSAGE);
+            assert_eq!(response.headers()[CACHE_CONTROL], "max-age=300");
+            let message = Message::deserialize(response.body()).unwrap();
+            assert!(message.is_response_to(&query));
+        }
+
+        let response = send(addresses.https, post(DNS_JSON, query.serialize())).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let response = send(addresses.https, get("https://localhost/dns-query?dns=%")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(addresses.https, get("https://localhost/resolve")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn json_queries_are_answered_in_the_json_format() {
+        let (addresses, mut queue) = testing::listen().await;
+        let uri = "https://localhost/dns-query?name=example.com&type=A&do=1";
+        let server = async {
+            let request = queue.recv().await.unwrap();
+            assert!(request.message.edns().unwrap().dnssec_ok());
+            testing::answer(request).await;
+        };
+        let (response, ()) = tokio::join!(send(addresses.https, get(uri)), server);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], DNS_JSON);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "Status": 0,
+                "TC": false,
+                "RD": true,
+                "RA": true,
+                "AD": false,
+                "CD": false,
+                "Question": [{ "name": "example.com.", "type": 1 }],
+                "Answer": [{ "name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1" }],
+            })
+        );
+
+        let uri = "https://localhost/dns-query?name=example.com&type=NOSUCHTYPE";
+        let response = send(addresses.https, get(uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::dns::Message;
+use crate::requests::Request;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{
+    Connection, ConnectionError, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream,
+    TokioRuntime, VarInt,
+};
+use rustls::ServerConfig;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, Semaphore};
+
+/// Closes a connection whose peer broke the protocol, RFC 9250 4.3.
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+
+/// Builds the configuration shared by the DNS over QUIC endpoints.
+///
+/// # Arguments
+///
+/// * `crypto` - The TLS configuration, offering the `doq` protocol.
+///
+/// * `idle_timeout` - How long a connection may go without any traffic before it is closed.
+pub(super) fn server_config(
+    mut crypto: ServerConfig,
+    idle_timeout: Duration,
+) -> io::Result<quinn::ServerConfig> {
+    // Accept 0-RTT data. Requests that are not safe to replay wait for the handshake instead.
+    crypto.max_early_data_size = u32::MAX;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(io::Error::other)?;
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let idle_timeout = idle_timeout.try_into().map_err(io::Error::other)?;
+    Arc::get_mut(&mut config.transport)
+        .expect("transport config is not shared yet")
+        .max_idle_timeout(Some(idle_timeout));
+    Ok(config)
+}
+
+/// Opens a DNS over QUIC endpoint on a bound UDP socket.
+pub(super) fn bind(
+    socket: std::net::UdpSocket,
+    config: quinn::ServerConfig,
+) -> io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(TokioRuntime),
+    )
+}
+
+/// Accepts connections on a DNS over QUIC endpoint and serves each on its own task.
+pub(super) async fn handle_quic(
+    endpoint: Endpoint,
+    connections: Arc<Semaphore>,
+    sender: Sender<Request>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        // Connections over the limit are refused straight away rather than left waiting.
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            eprintln!(
+                "Refusing QUIC connection from {}: connection limit reached",
+                incoming.remote_address()
+            );
+            incoming.refuse();
+            continue;
+        };
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let addr = incoming.remote_address();
+            if let Err(e) = serve_connection(incoming, &sender).await {
+                eprintln!("QUIC connection from {} failed: {}", addr, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Completes the handshake and reads one query from every stream the client opens.
+async fn serve_connection(incoming: Incoming, sender: &Sender<Request>) -> io::Result<()> {
+    let connecting = incoming.accept()?;
+    let (handshake_done, handshake) = watch::channel(false);
+    let connection = match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            let established = connection.clone();
+            tokio::spawn(async move {
+                // A replayed handshake never completes, as the attacker does not have the keys.
+                accepted.await;
+                if established.close_reason().is_none() {
+                    let _ = handshake_done.send(true);
+                }
+            });
+            connection
+        }
+        Err(connecting) => {
+            let connection = connecting.await?;
+            let _ = handshake_done.send(true);
+            connection
+        }
+    };
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(_))
+            | Err(ConnectionError::ConnectionClosed(_))
+            | Err(ConnectionError::LocallyClosed)
+            | Err(ConnectionError::TimedOut) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let connection = connection.clone();
+        let handshake = handshake.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = read_query(&connection, send, recv, handshake, &sender).await {
+                eprintln!(
+                    "QUIC stream from {} failed: {}",
+                    connection.remote_address(),
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Reads the single length-prefixed query on a stream, RFC 9250 4.2, and queues it.
+async fn read_query(
+    connection: &Connection,
+    send: SendStream,
+    mut recv: RecvStream,
+    mut handshake: watch::Receiver<bool>,
+    sender: &Sender<Request>,
+) -> io::Result<()> {
+    let frame = recv
+        .read_to_end(u16::MAX as usize + 2)
+        .await
+        .map_err(io::Error::other)?;
+    let message = match frame.split_first_chunk::<2>() {
+        Some((length, message)) if u16::from_be_bytes(*length) as usize == message.len() => {
+            Message::deserialize(message).ok()
+        }
+        _ => None,
+    };
+    // A malformed query, or one with a non-zero ID, is a protocol error, RFC 9250 4.2.1.
+    let Some(message) = message.filter(|m| m.id() == 0) else {
+        connection.close(DOQ_PROTOCOL_ERROR, b"malformed query");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed DNS over QUIC query",
+        ));
+    };
+
+    // Only standard queries can be replayed without harm, RFC 9250 4.5.
+    if message.opcode() != 0 && handshake.wait_for(|done| *done).await.is_err() {
+        return Ok(());
+    }
+    if sender.send(Request::new_quic(send, message)).await.is_err() {
+        eprintln!("Failed to send QUIC request through channel");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::RecordType;
+    use crate::listeners::testing::{self, CERTIFICATE_FILE};
+    use crate::tls_config::{self, ALPN_DOQ};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn queries_with_a_nonzero_id_close_the_connection() {
+        let (addresses, mut queue) = testing::listen().await;
+        let crypto = tls_config::client_config(Some(CERTIFICATE_FILE), &[], &[ALPN_DOQ]).unwrap();
+        let crypto = QuicClientConfig::try_from(crypto).unwrap();
+        let endpoint = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let connection = endpoint
+            .connect_with(
+                quinn::ClientConfig::new(Arc::new(crypto)),
+                addresses.quic,
+                "localhost",
+            )
+            .unwrap()
+            .await
+            .unwrap();
+
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+        let (mut send, _recv) = connection.open_bi().await.unwrap();
+        send.write_all(&query.serialize_framed()).await.unwrap();
+        send.finish().unwrap();
+
+        match connection.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR)
+            }
+            e => panic!("Unexpected close: {}", e),
+        }
+        assert!(queue.try_recv().is_err());
+    }
+}
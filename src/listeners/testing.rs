@@ -0,0 +1,103 @@
+use super::Listeners;
+use crate::dns::{Message, RCode, RData, ResourceRecord};
+use crate::requests::Request;
+use crate::settings::{HttpMethod, ListenersSettings, UpstreamProtocol, UpstreamSettings};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::sync::mpsc::Receiver;
+
+/// The self-signed certificate for `localhost` and 127.0.0.1 that the test listeners present.
+pub(crate) const CERTIFICATE_FILE: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.pem");
+pub(crate) const KEY_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key");
+/// The base64 SHA-256 digest of the certificate's SubjectPublicKeyInfo.
+pub(crate) const SPKI_PIN: &str = "wQOLWpDbYOkUAUZoVZMMkElervOac04IXCm3UyykFrE=";
+
+/// The address every name resolves to in `answer`.
+pub(crate) const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// The addresses of the encrypted listeners started by `listen`.
+pub(crate) struct Addresses {
+    pub(crate) tls: SocketAddr,
+    pub(crate) https: SocketAddr,
+    pub(crate) quic: SocketAddr,
+}
+
+/// Starts DNS over TLS, HTTPS, with the JSON API, and QUIC listeners on free loopback ports.
+///
+/// # Returns
+///
+/// The addresses of the listeners, and the queue their requests arrive on, which the test
+/// answers in place of the resolver.
+pub(crate) async fn listen() -> (Addresses, Receiver<Request>) {
+    let encrypted = |extra: &str| {
+        format!(
+            r#"{{ enabled = true, address = "127.0.0.1", port = 0, certificate_file = "{}", key_file = "{}"{} }}"#,
+            CERTIFICATE_FILE, KEY_FILE, extra
+        )
+    };
+    let settings = format!(
+        r#"
+        udp = {{ enabled = false, address = "127.0.0.1", port = 0 }}
+        tcp = {{ enabled = false, address = "127.0.0.1", port = 0 }}
+        tls = {}
+        https = {}
+        quic = {}
+        "#,
+        encrypted(""),
+        encrypted(", json = true"),
+        encrypted("")
+    );
+    let settings: ListenersSettings = config::Config::builder()
+        .add_source(config::File::from_str(&settings, config::FileFormat::Toml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap();
+
+    let listeners = Listeners::new(&settings).await.unwrap();
+    let addresses = Addresses {
+        tls: listeners.tls_listeners[0].listener.local_addr().unwrap(),
+        https: listeners.https_listeners[0].listener.local_addr().unwrap(),
+        quic: listeners.quic_listeners[0].0.local_addr().unwrap(),
+    };
+    (addresses, listeners.listen().await.unwrap())
+}
+
+/// Answers every request from the queue with `answer`.
+pub(crate) fn answer_all(mut queue: Receiver<Request>) {
+    tokio::spawn(async move {
+        while let Some(request) = queue.recv().await {
+            answer(request).await;
+        }
+    });
+}
+
+/// Answers a request with an A record of `ADDRESS` for the name it asks for.
+pub(crate) async fn answer(request: Request) {
+    let name = request.message.qname_to_string();
+    let record = ResourceRecord::new(&name, 300, RData::A(ADDRESS)).unwrap();
+    let response =
+        Message::new_response(&request.message, RCode::NOERROR, vec![record], Vec::new());
+    request.send_response(&response).await.unwrap();
+}
+
+/// Creates the settings of an upstream that connects to a test listener and trusts its
+/// certificate. HTTPS upstreams use the listener's `/dns-query` endpoint.
+pub(crate) fn upstream(protocol: UpstreamProtocol, address: SocketAddr) -> UpstreamSettings {
+    UpstreamSettings {
+        address: address.ip().to_string(),
+        port: address.port(),
+        protocol,
+        timeout: 1000,
+        weight: 1,
+        tls_name: Some("localhost".to_string()),
+        ca_file: Some(CERTIFICATE_FILE.to_string()),
+        spki_pins: Vec::new(),
+        connections: 1,
+        url: Some(format!(
+            "https://localhost:{}/dns-query{{?dns}}",
+            address.port()
+        )),
+        method: HttpMethod::POST,
+    }
+}
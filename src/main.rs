@@ -1,15 +1,7 @@
+use hermes_dns::{listeners, resolver, settings};
 use std::io::{self, Write};
 use std::sync::Arc;
 
-mod blocklist;
-mod cache;
-mod dns;
-mod listeners;
-mod resolver;
-mod requests;
-mod settings;
-mod upstreams;
-
 #[tokio::main]
 async fn main() {
     let settings = settings::Settings::load().expect("Failed to load settings");
@@ -21,9 +13,9 @@ async fn main() {
         .expect("Failed to listen on listeners");
 
     let resolver =
-        resolver::Resolver::new(queue, &settings.resolver).expect("Failed to create resolver");
+        resolver::Resolver::new(&settings.resolver).expect("Failed to create resolver");
 
-        resolver.start().await;
+        Arc::new(resolver).start(queue).await;
 
     println!("Press Enter to exit...");
     io::stdout().flush().expect("Failed to flush stdout");
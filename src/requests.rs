@@ -1,17 +1,41 @@
 use crate::dns::Message;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, AsyncWrite};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+/// The write half of a stream connection, plain TCP or TLS, shared by every request read from it.
+pub struct StreamWriter {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    /// How long writing one response may take, including the wait for earlier ones.
+    write_timeout: Duration,
+    /// Limits the queries in flight on the connection. Closed once the connection is given up on.
+    in_flight: Arc<Semaphore>,
+}
 
 pub enum ConnectionInfo {
     UDP {
         socket: Arc<UdpSocket>,
         addr: SocketAddr,
     },
+    /// A connection carrying length-prefixed messages, either plain TCP or DNS over TLS.
     TCP {
-        stream: Arc<Mutex<TcpStream>>,
+        writer: Arc<StreamWriter>,
+        /// Counts the request as in flight on its connection until it is dropped.
+        _in_flight: OwnedSemaphorePermit,
+    },
+    /// A DNS over HTTPS request, answered by handing the response back to the HTTP exchange
+    /// that carried it, which encodes it for the client.
+    HTTPS {
+        responder: Mutex<Option<oneshot::Sender<Message>>>,
+    },
+    /// A DNS over QUIC request, answered on the stream it arrived on, RFC 9250 4.2.
+    QUIC {
+        stream: Mutex<Option<quinn::SendStream>>,
     },
 }
 
@@ -28,26 +52,164 @@ impl Request {
         }
     }
 
-    pub fn new_tcp(stream: Arc<Mutex<TcpStream>>, message: Message) -> Self {
+    pub fn new_tcp(
+        writer: Arc<StreamWriter>,
+        in_flight: OwnedSemaphorePermit,
+        message: Message,
+    ) -> Self {
+        Self {
+            connection_info: ConnectionInfo::TCP {
+                writer,
+                _in_flight: in_flight,
+            },
+            message,
+        }
+    }
+
+    /// Creates a request received over HTTPS.
+    ///
+    /// # Returns
+    ///
+    /// The request, and the receiver its response is delivered to.
+    pub fn new_https(message: Message) -> (Self, oneshot::Receiver<Message>) {
+        let (responder, receiver) = oneshot::channel();
+        let request = Self {
+            connection_info: ConnectionInfo::HTTPS {
+                responder: Mutex::new(Some(responder)),
+            },
+            message,
+        };
+        (request, receiver)
+    }
+
+    pub fn new_quic(stream: quinn::SendStream, message: Message) -> Self {
         Self {
-            connection_info: ConnectionInfo::TCP { stream },
+            connection_info: ConnectionInfo::QUIC {
+                stream: Mutex::new(Some(stream)),
+            },
             message,
         }
     }
 
-    pub async fn send_response(&self, response: &[u8]) -> std::io::Result<()> {
+    pub async fn send_response(&self, response: &Message) -> std::io::Result<()> {
         match &self.connection_info {
             ConnectionInfo::UDP { socket, addr } => {
-                socket.send_to(response, addr).await?;
+                // Responses that exceed the client's advertised payload size are truncated
+                // so that the client retries over TCP instead of receiving a fragmented datagram.
+                let response = response.serialize_truncated(self.message.max_udp_response_size());
+                socket.send_to(&response, addr).await?;
             }
-            ConnectionInfo::TCP { stream } => {
-                let mut stream = stream.lock().await;
-                stream.write_all(response).await?;
-                stream.flush().await?;
-                // Consider if I need to close the stream, adjust accordingly
-                // For protocols that keep the connection open, I might not close here
+            ConnectionInfo::TCP { writer, .. } => {
+                writer.write(&response.serialize_framed()).await?;
+            }
+            ConnectionInfo::HTTPS { responder } => {
+                let responder = responder.lock().await.take().ok_or_else(|| {
+                    std::io::Error::other("A response was already sent for this request")
+                })?;
+                responder.send(response.clone()).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "The HTTPS client is no longer waiting",
+                    )
+                })?;
+            }
+            ConnectionInfo::QUIC { stream } => {
+                let mut stream = stream.lock().await.take().ok_or_else(|| {
+                    std::io::Error::other("A response was already sent for this request")
+                })?;
+                // The response ends the stream, RFC 9250 4.2.
+                stream.write_all(&response.serialize_framed()).await?;
+                stream.finish()?;
             }
         }
         Ok(())
     }
 }
+
+impl StreamWriter {
+    /// Wraps the write half of a connection so that every request read from it can respond.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The write half of the connection.
+    ///
+    /// * `write_timeout` - How long writing one response may take before the connection is given up.
+    ///
+    /// * `max_in_flight` - The number of queries from the connection that may be resolved at once.
+    pub fn new(
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        write_timeout: Duration,
+        max_in_flight: usize,
+    ) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            write_timeout,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+        }
+    }
+
+    /// Waits until another query from the connection may be in flight.
+    ///
+    /// # Returns
+    ///
+    /// The permit to hold while the query is resolved, or `None` once the connection is given up on,
+    /// after which no more queries should be read from it.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        self.in_flight.clone().acquire_owned().await.ok()
+    }
+
+    /// Writes a whole frame under the lock, so that pipelined responses never interleave.
+    ///
+    /// A client that does not read its responses would otherwise hold every request from the
+    /// connection, and the resolver's permits with them. If the write times out, the connection is
+    /// given up on: later writes fail at once and the reader stops.
+    pub async fn write(&self, frame: &[u8]) -> io::Result<()> {
+        let write = async {
+            let mut writer = self.writer.lock().await;
+            // A timed out write may have left part of a frame behind.
+            if self.in_flight.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "The connection was given up on",
+                ));
+            }
+            writer.write_all(frame).await?;
+            writer.flush().await
+        };
+        match timeout(self.write_timeout, write).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.in_flight.close();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out writing response",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clients_that_stop_reading_are_given_up_on() {
+        // The client never reads, so the pipe fills up after the first response.
+        let (_client, server) = tokio::io::duplex(16);
+        let writer = StreamWriter::new(server, Duration::from_millis(50), 2);
+
+        let first = writer.reserve().await.unwrap();
+        let _second = writer.reserve().await.unwrap();
+        assert!(writer.in_flight.try_acquire().is_err());
+        writer.write(&[0; 16]).await.unwrap();
+        drop(first);
+        assert!(writer.reserve().await.is_some());
+
+        let error = writer.write(&[0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(writer.reserve().await.is_none());
+        let error = writer.write(&[0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
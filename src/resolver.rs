@@ -1,73 +1,229 @@
 use crate::blocklist::Blocklist;
-use crate::cache::Cache;
-use crate::dns::Message as DNSMessage;
+use crate::cache::{Cache, Cached};
+use crate::dns::{Message as DNSMessage, RCode};
 use crate::requests::Request;
-use crate::settings::{ResolverSettings, UpstreamSettings};
+use crate::settings::ResolverSettings;
 use crate::upstreams::Upstreams;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::Semaphore;
 use std::io;
 use std::sync::Arc;
 
 pub struct Resolver {
     blocklist: Blocklist,
-    queue_receiver: Receiver<Request>,
     cache: Cache,
     upstreams: Upstreams,
+    /// Limits how many requests are processed at once.
+    request_slots: Arc<Semaphore>,
 }
 
 impl Resolver {
-    pub fn new(
-        queue_receiver: Receiver<Request>,
-        resolver_settings: &ResolverSettings,
-    ) -> Result<Self, io::Error> {
+    pub fn new(resolver_settings: &ResolverSettings) -> Result<Self, io::Error> {
         Ok(Self {
             blocklist: Blocklist::new(),
-            queue_receiver,
             cache: Cache::new(&resolver_settings.cache),
-            upstreams: Upstreams::new(&resolver_settings.upstreams),
+            upstreams: Upstreams::new(
+                &resolver_settings.upstreams,
+                resolver_settings.strategy,
+                &resolver_settings.health_check,
+            )?,
+            // Without a single slot no request would ever be processed.
+            request_slots: Arc::new(Semaphore::new(
+                resolver_settings.max_concurrent_requests.max(1),
+            )),
         })
     }
 
-    pub async fn start(mut self: Self) {
-        while let Some(request) = self.queue_receiver.recv().await {
-            self.process_message(request).await;
+    /// Processes requests from the queue until every listener has shut down.
+    ///
+    /// Each request is handled in its own task so a slow upstream only delays its own clients.
+    /// Once `max_concurrent_requests` are in flight the queue is no longer drained, which in
+    /// turn makes the listeners wait before accepting more work.
+    pub async fn start(self: Arc<Self>, mut queue_receiver: Receiver<Request>) {
+        self.upstreams.spawn_health_checks();
+
+        loop {
+            let permit = self
+                .request_slots
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Request semaphore is never closed");
+            let Some(request) = queue_receiver.recv().await else {
+                break;
+            };
+
+            let resolver = self.clone();
+            tokio::spawn(async move {
+                resolver.process_message(request).await;
+                drop(permit);
+            });
         }
     }
 
-    async fn process_message(&self, request: Request) {
+    async fn process_message(self: Arc<Self>, request: Request) {
         let request_domain = request.message.qname_to_string();
 
+        // Answering a response could start an endless exchange with whoever the source claims to
+        // be, so responses are dropped unanswered, RFC 1035 4.1.1.
+        if request.message.is_response() {
+            eprintln!(
+                "Dropping a response received as a request for {}",
+                request_domain
+            );
+            return;
+        }
+
+        // Only EDNS version 0 is defined, so a later one is refused rather than forwarded as if
+        // it were version 0, RFC 6891 6.1.3.
+        let version = request.message.edns().map_or(0, |edns| edns.version());
+        if version > 0 {
+            let response =
+                DNSMessage::new_response(&request.message, RCode::BADVERS, Vec::new(), Vec::new());
+            Self::reply(&request, response, "bad version").await;
+            return;
+        }
+
         if self.blocklist.contains(&request_domain) {
             println!("Domain {} is blocked.", request_domain);
             return;
         }
 
-        if let Some(cached_response) = self.cache.query(&request_domain) {
-            println!("Cache hit for domain: {}", request_domain);
-            // Asynchronously send cached_response back to the client
-            // Ensure send_response is awaited
-            //if let Err(e) = request.send_response(&cached_response.serialize()).await {
-            //    eprintln!("Failed to send cached response: {}", e);
-            //}
+        if let Some(cached) = self.cache.query(&request.message) {
+            let (response, refresh) = match cached {
+                Cached::Fresh { response, refresh } => {
+                    println!("Cache hit for domain: {}", request_domain);
+                    (response, refresh)
+                }
+                Cached::Stale { response, refresh } => {
+                    println!("Serving stale response for domain: {}", request_domain);
+                    (response, refresh)
+                }
+            };
+            Self::reply(&request, response, "cached").await;
+            // The client has its answer, so the upstreams can take their time, without holding
+            // on to the request slot.
+            if refresh {
+                tokio::spawn(async move {
+                    self.resolve(&request.message).await;
+                });
+            }
             return;
         }
 
-        if let Some(response) = self.upstreams.query(&request_domain) {
-            println!("Response from upstream for domain: {}", request_domain);
-            // Asynchronously send response back to the client
-            // Ensure send_response is awaited
-            //if let Err(e) = request.send_response(&response.serialize()).await {
-            //    eprintln!("Failed to send upstream response: {}", e);
-            //}
+        let response = match self.resolve(&request.message).await {
+            Some(response) if response.rcode() != RCode::SERVFAIL => {
+                println!("Response from upstream for domain: {}", request_domain);
+                Self::reply(&request, response, "upstream").await;
+                return;
+            }
+            response => response,
+        };
+
+        // Every upstream failed or timed out, so fall back to an expired answer, RFC 8767.
+        if let Some(stale) = self.cache.query_stale(&request.message) {
+            println!(
+                "No upstream answered for domain {}, serving stale response.",
+                request_domain
+            );
+            Self::reply(&request, stale, "stale").await;
             return;
         }
 
-        // Handling domain not found
-        println!("Domain {} not found.", request_domain);
-        let response = DNSMessage::new_not_found_response(&request.message);
-        let response_data = response.serialize();
-        if let Err(e) = request.send_response(&response_data).await {
-            eprintln!("Failed to send not found response: {}", e);
+        // Handling every upstream failing
+        println!("No upstream answered for domain {}.", request_domain);
+        let response =
+            response.unwrap_or_else(|| DNSMessage::new_server_failure_response(&request.message));
+        Self::reply(&request, response, "server failure").await;
+    }
+
+    /// Queries the upstreams and caches their response.
+    async fn resolve(&self, request: &DNSMessage) -> Option<DNSMessage> {
+        let response = self.upstreams.query(request).await?;
+        self.cache.insert(request, &response);
+        Some(response)
+    }
+
+    /// Addresses a response to the client's request and sends it.
+    async fn reply(request: &Request, mut response: DNSMessage, kind: &str) {
+        response.set_reply_to(&request.message);
+        if let Err(e) = request.send_response(&response).await {
+            eprintln!("Failed to send {} response: {}", kind, e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::RecordType;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    /// Creates a resolver without a cache whose only upstream is a UDP socket on `port`.
+    fn resolver(port: u16) -> Arc<Resolver> {
+        let settings = format!(
+            r#"
+            cache = {{ enabled = false, size = 0 }}
+            upstreams = [{{ address = "127.0.0.1", port = {}, protocol = "udp" }}]
+            "#,
+            port
+        );
+        let settings: ResolverSettings = config::Config::builder()
+            .add_source(config::File::from_str(&settings, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        Arc::new(Resolver::new(&settings).unwrap())
+    }
+
+    #[tokio::test]
+    async fn responses_are_not_answered() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(upstream.local_addr().unwrap().port());
+        let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_address = client.local_addr().unwrap();
+
+        let query = DNSMessage::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+        let response = DNSMessage::new_server_failure_response(&query);
+        let request = Request::new_udp(listener.clone(), client_address, response);
+        resolver.clone().process_message(request).await;
+
+        let mut buf = [0u8; 512];
+        assert!(upstream.try_recv(&mut buf).is_err());
+        assert!(client.try_recv(&mut buf).is_err());
+
+        // A query for the same name is forwarded.
+        let request = Request::new_udp(listener, client_address, query);
+        tokio::spawn(resolver.process_message(request));
+        let received = tokio::time::timeout(Duration::from_secs(5), upstream.recv(&mut buf)).await;
+        assert!(received.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn later_edns_versions_get_a_bad_version_response() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(upstream.local_addr().unwrap().port());
+        let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut query = DNSMessage::new_simple_query("example.com", RecordType::A, 0x1234)
+            .unwrap()
+            .serialize();
+        query[11] = 1; // ARCOUNT
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 1, 0, 0, 0, 0]); // OPT, version 1
+        let query = DNSMessage::deserialize(&query).unwrap();
+        let request = Request::new_udp(listener, client.local_addr().unwrap(), query);
+        resolver.process_message(request).await;
+
+        let mut buf = [0u8; 512];
+        let len = client.recv(&mut buf).await.unwrap();
+        let response = DNSMessage::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.rcode(), RCode::BADVERS);
+        assert_eq!(response.edns().unwrap().version(), 0);
+        assert!(upstream.try_recv(&mut buf).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use config::Config;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -9,34 +9,296 @@ pub struct Settings {
 
 #[derive(Debug, Deserialize)]
 pub struct ListenersSettings {
-    pub udp: ListenerSettings,
-    pub tcp: ListenerSettings,
+    pub udp: UdpListenerSettings,
+    pub tcp: TcpListenerSettings,
+    /// DNS over TLS, RFC 7858, conventionally served on port 853.
+    pub tls: Option<TlsListenerSettings>,
+    /// DNS over HTTPS, RFC 8484, served at `/dns-query`, conventionally on port 443.
+    pub https: Option<HttpsListenerSettings>,
+    /// DNS over QUIC, RFC 9250, conventionally served on UDP port 853.
+    pub quic: Option<TlsListenerSettings>,
 }
 
+/// Where a listener accepts requests, common to every protocol.
 #[derive(Debug, Deserialize)]
-pub struct ListenerSettings {
+pub struct BindSettings {
     pub enabled: bool,
-    pub address: String,
+    /// The addresses to listen on, given as a single address or a list such as
+    /// `["127.0.0.1", "::1"]`. Host names are bound on every address they resolve to.
+    #[serde(alias = "address", deserialize_with = "one_or_many")]
+    pub addresses: Vec<String>,
     pub port: u16,
+    /// Whether IPv6 sockets refuse IPv4 traffic (`IPV6_V6ONLY`). Unset to keep the system default,
+    /// which on Linux lets a socket bound to `::` also accept IPv4.
+    pub v6only: Option<bool>,
+    /// The network interface the sockets are bound to, such as `eth0`. Only supported on Linux.
+    pub interface: Option<String>,
+}
+
+/// Limits on the connections of a connection-oriented listener.
+#[derive(Debug, Deserialize)]
+pub struct ConnectionSettings {
+    /// Seconds a connection may stay idle before it is closed. A response that cannot be written
+    /// within this time also closes the connection of a TCP or TLS listener.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// The maximum number of connections served at once.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+}
+
+/// The certificate an encrypted listener presents, required once the listener is enabled.
+#[derive(Debug, Deserialize)]
+pub struct CertificateSettings {
+    /// The PEM certificate chain presented to clients.
+    pub certificate_file: Option<String>,
+    /// The PEM private key of the certificate.
+    pub key_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpListenerSettings {
+    #[serde(flatten)]
+    pub bind: BindSettings,
+    /// The number of sockets opened on each address, each with its own receive loop. More than one
+    /// share the address with `SO_REUSEPORT`, which needs a Unix system.
+    #[serde(default = "default_sockets")]
+    pub sockets: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpListenerSettings {
+    #[serde(flatten)]
+    pub bind: BindSettings,
+    #[serde(flatten)]
+    pub connections: ConnectionSettings,
+}
+
+/// The settings of the DNS over TLS and DNS over QUIC listeners.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListenerSettings {
+    #[serde(flatten)]
+    pub bind: BindSettings,
+    #[serde(flatten)]
+    pub connections: ConnectionSettings,
+    #[serde(flatten)]
+    pub certificate: CertificateSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpsListenerSettings {
+    #[serde(flatten)]
+    pub bind: BindSettings,
+    #[serde(flatten)]
+    pub connections: ConnectionSettings,
+    #[serde(flatten)]
+    pub certificate: CertificateSettings,
+    /// Whether to also answer `application/dns-json` GET requests.
+    #[serde(default)]
+    pub json: bool,
+}
+
+/// Accepts either a single string or a list of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn default_idle_timeout() -> u64 {
+    10
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_sockets() -> usize {
+    1
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ResolverSettings {
     pub cache: CacheSettings,
     pub upstreams: Vec<UpstreamSettings>,
+    /// How the upstream for each request is chosen.
+    #[serde(default)]
+    pub strategy: UpstreamStrategy,
+    #[serde(default)]
+    pub health_check: HealthCheckSettings,
+    /// The maximum number of requests processed at once. At least one is always allowed.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+}
+
+fn default_max_concurrent_requests() -> usize {
+    1024
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStrategy {
+    /// Try the upstreams in the order they are configured.
+    #[default]
+    Failover,
+    /// Start with the next upstream in turn for every request.
+    RoundRobin,
+    /// Start with an upstream picked at random, in proportion to its weight.
+    Weighted,
+    /// Start with the upstream with the lowest average response time.
+    Fastest,
+    /// Query every upstream at once and use the first answer.
+    Race,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CacheSettings {
     pub enabled: bool,
     pub size: usize,
+    /// The number of independently locked parts the cache is split into, so that requests on
+    /// different cores rarely wait for each other. 0 to use four per CPU.
+    #[serde(default)]
+    pub shards: usize,
+    /// Seconds after expiring that a response may still be served when no upstream answers,
+    /// RFC 8767. 0 to never serve stale responses.
+    #[serde(default = "default_max_stale")]
+    pub max_stale: u64,
+    /// The TTL of stale responses in seconds, which is also how long to wait before asking the
+    /// upstreams to refresh them again.
+    #[serde(default = "default_stale_ttl")]
+    pub stale_ttl: u32,
+    /// The number of times a response must be served from the cache before it is refreshed
+    /// ahead of expiring.
+    #[serde(default = "default_prefetch_min_hits")]
+    pub prefetch_min_hits: u32,
+    /// How much of its TTL, in percent, a popular response has left when it is refreshed.
+    /// 0 to never prefetch.
+    #[serde(default = "default_prefetch_percent")]
+    pub prefetch_percent: u8,
+}
+
+fn default_max_stale() -> u64 {
+    86400
+}
+
+fn default_stale_ttl() -> u32 {
+    30
+}
+
+fn default_prefetch_min_hits() -> u32 {
+    3
+}
+
+fn default_prefetch_percent() -> u8 {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HealthCheckSettings {
+    pub enabled: bool,
+    /// The name queried to check that an upstream is answering.
+    pub probe_name: String,
+    /// The record type queried for `probe_name`, e.g. "NS" or "A".
+    pub probe_type: String,
+    /// Seconds between probes of an upstream that is up. At least 1.
+    pub interval: u64,
+    /// Consecutive failed queries or probes after which an upstream is marked down.
+    pub failure_threshold: u32,
+    /// Seconds before a down upstream is first probed again. Doubles after every failed probe.
+    /// At least 1.
+    pub initial_backoff: u64,
+    /// The longest time in seconds between probes of a down upstream. At least 1.
+    pub max_backoff: u64,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            probe_name: ".".to_string(),
+            probe_type: "NS".to_string(),
+            interval: 30,
+            failure_threshold: 3,
+            initial_backoff: 5,
+            max_backoff: 300,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UpstreamSettings {
     pub address: String,
     pub port: u16,
-    pub protocol: String,
+    pub protocol: UpstreamProtocol,
+    /// Milliseconds to wait for a response before the query is considered failed.
+    #[serde(default = "default_upstream_timeout")]
+    pub timeout: u64,
+    /// The relative share of requests sent to this upstream by the weighted strategy.
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+    /// The name the server's certificate is checked against and sent as SNI. Defaults to the
+    /// host of `url` for HTTPS upstreams and to `address` otherwise.
+    pub tls_name: Option<String>,
+    /// A PEM bundle of CA certificates to trust instead of the built-in web PKI roots.
+    pub ca_file: Option<String>,
+    /// Base64 SHA-256 digests of the accepted SubjectPublicKeyInfo, RFC 7858 4.2. Empty to not pin.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    /// The number of persistent connections kept open to a TLS upstream. QUIC upstreams
+    /// multiplex all queries over a single connection.
+    #[serde(default = "default_upstream_connections")]
+    pub connections: usize,
+    /// The URI template of an HTTPS upstream, such as `https://dns.example/dns-query{?dns}`, RFC 8484 4.1.
+    /// The host is only used for TLS and the HTTP request, connections always go to `address`.
+    pub url: Option<String>,
+    /// The HTTP method used to send queries to an HTTPS upstream.
+    #[serde(default)]
+    pub method: HttpMethod,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    UDP,
+    TCP,
+    TLS,
+    HTTPS,
+    QUIC,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    /// The query is the request body. Requests are never cached by HTTP caches.
+    #[default]
+    POST,
+    /// The query is base64url-encoded into the `dns` parameter, which HTTP caches can store.
+    GET,
+}
+
+fn default_upstream_timeout() -> u64 {
+    2000
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn default_upstream_connections() -> usize {
+    2
 }
 
 impl Settings {
@@ -0,0 +1,196 @@
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::Arc;
+
+/// The ALPN protocol identifier for DNS over TLS.
+pub const ALPN_DOT: &[u8] = b"dot";
+/// The ALPN protocol identifier for DNS over QUIC, RFC 9250 4.1.1.
+pub const ALPN_DOQ: &[u8] = b"doq";
+/// The ALPN protocol identifier for HTTP/2, the earliest version recommended for DoH, RFC 8484 5.2.
+pub const ALPN_H2: &[u8] = b"h2";
+/// The ALPN protocol identifier for HTTP/1.1, which DoH clients may still fall back to.
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// The media type of DNS messages in wire format, RFC 8484 6.
+pub const DNS_MESSAGE: &str = "application/dns-message";
+/// The media type of the JSON format popularised by Google and Cloudflare.
+pub const DNS_JSON: &str = "application/dns-json";
+
+/// Builds the TLS configuration used to connect to encrypted upstreams.
+///
+/// # Arguments
+///
+/// * `ca_file` - A PEM bundle of CA certificates to trust, or `None` for the built-in web PKI roots.
+/// * `spki_pins` - Base64 SHA-256 digests of SubjectPublicKeyInfo, one of which must appear in the
+///   server's certificate chain. Certificates are still validated against the CAs, so a
+///   self-signed server is pinned by also giving its certificate as `ca_file`.
+/// * `alpn` - The application protocols to offer, most preferred first.
+pub fn client_config(
+    ca_file: Option<&str>,
+    spki_pins: &[String],
+    alpn: &[&[u8]],
+) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid(path, e))? {
+                roots
+                    .add(cert.map_err(|e| invalid(path, e))?)
+                    .map_err(|e| invalid(path, e))?;
+            }
+            if roots.is_empty() {
+                return Err(invalid(path, "no certificates found"));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(io::Error::other)?;
+    let verifier: Arc<dyn ServerCertVerifier> = if spki_pins.is_empty() {
+        webpki
+    } else {
+        Arc::new(PinnedVerifier::new(webpki, spki_pins)?)
+    };
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// Builds the TLS configuration used by encrypted listeners.
+///
+/// # Arguments
+///
+/// * `certificate_file` - The PEM certificate chain presented to clients, leaf first.
+/// * `key_file` - The PEM private key of the leaf certificate.
+/// * `alpn` - The application protocols to accept. Clients that offer none are still accepted.
+pub fn server_config(
+    certificate_file: &str,
+    key_file: &str,
+    alpn: &[&[u8]],
+) -> io::Result<ServerConfig> {
+    let certificates = CertificateDer::pem_file_iter(certificate_file)
+        .map_err(|e| invalid(certificate_file, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(certificate_file, e))?;
+    if certificates.is_empty() {
+        return Err(invalid(certificate_file, "no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| invalid(key_file, e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| invalid(certificate_file, e))?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// Accepts a server only if its chain passes normal validation and contains a pinned key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    /// SHA-256 digests of the accepted SubjectPublicKeyInfo.
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedVerifier {
+    fn new(inner: Arc<WebPkiServerVerifier>, spki_pins: &[String]) -> io::Result<Self> {
+        let pins = spki_pins
+            .iter()
+            .map(|pin| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(pin)
+                    .ok()
+                    .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+                    .ok_or_else(|| invalid(pin, "not a base64 SHA-256 digest"))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { inner, pins })
+    }
+
+    fn is_pinned(&self, cert: &CertificateDer) -> bool {
+        match x509_parser::parse_x509_certificate(cert) {
+            Ok((_, parsed)) => {
+                let digest: [u8; 32] = Sha256::digest(parsed.public_key().raw).into();
+                self.pins.contains(&digest)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.is_pinned(cert))
+        {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn invalid(what: &str, error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", what, error))
+}
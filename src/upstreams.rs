@@ -1,26 +1,704 @@
-use crate::settings::UpstreamSettings;
+use crate::dns::{Message, RCode, RecordType};
+use crate::settings::{HealthCheckSettings, UpstreamProtocol, UpstreamSettings, UpstreamStrategy};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
+
+mod https;
+mod quic;
+mod slot;
+mod tls;
 
 pub struct Upstreams {
-    upstreams: Vec<Upstream>,
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: UpstreamStrategy,
+    /// The upstream the round-robin strategy starts with next.
+    next: AtomicUsize,
+    /// The query sent to check on each upstream, or `None` if health checks are disabled.
+    probe: Option<Arc<Message>>,
+    /// Time between probes of an upstream that is up.
+    probe_interval: Duration,
 }
 
 impl Upstreams {
-    pub fn new(upstream_settings: &[UpstreamSettings]) -> Self {
+    pub fn new(
+        upstream_settings: &[UpstreamSettings],
+        strategy: UpstreamStrategy,
+        health_check: &HealthCheckSettings,
+    ) -> io::Result<Self> {
+        // Without a delay, probes and trial queries would follow each other without pause.
+        let delays = [
+            health_check.interval,
+            health_check.initial_backoff,
+            health_check.max_backoff,
+        ];
+        if delays.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The health check interval, initial_backoff and max_backoff must be at least 1 second",
+            ));
+        }
+        let policy = HealthPolicy {
+            failure_threshold: health_check.failure_threshold.max(1),
+            initial_backoff: Duration::from_secs(health_check.initial_backoff),
+            max_backoff: Duration::from_secs(health_check.max_backoff),
+        };
         let upstreams = upstream_settings
             .iter()
-            .map(|us| Upstream {
-                address: us.address.clone(),
-            })
-            .collect();
+            .map(|us| Upstream::new(us, policy).map(Arc::new))
+            .collect::<io::Result<_>>()?;
 
-        Self { upstreams }
+        let probe = if health_check.enabled {
+            let rtype = RecordType::from_name(&health_check.probe_type).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid health check type: {}", health_check.probe_type),
+                )
+            })?;
+            let message = Message::new_simple_query(&health_check.probe_name, rtype, 0)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Some(Arc::new(message))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            probe,
+            probe_interval: Duration::from_secs(health_check.interval),
+        })
     }
 
-    pub fn query(&self, _domain: &str) -> Option<String> {
-        None
+    /// Starts a background task per upstream that probes it on an interval while it is up, and
+    /// with exponential backoff while it is down, so that it can be readmitted once it recovers.
+    pub fn spawn_health_checks(&self) {
+        let Some(probe) = &self.probe else {
+            return;
+        };
+
+        for upstream in &self.upstreams {
+            let upstream = upstream.clone();
+            let probe = probe.clone();
+            let interval = self.probe_interval;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(upstream.next_probe_delay(interval)).await;
+                    if let Err(e) = upstream.probe(&probe).await {
+                        eprintln!(
+                            "Health probe of upstream {} failed: {}",
+                            upstream.address, e
+                        );
+                    }
+                }
+            });
+        }
+    }
+
+    /// Forwards a client's request to the upstreams according to the configured strategy.
+    ///
+    /// Every strategy other than `Race` only decides which upstream is tried first; the others
+    /// are still tried in turn if it fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client's request.
+    ///
+    /// # Returns
+    ///
+    /// The first valid response other than SERVFAIL, still addressed to our own query. If every
+    /// upstream failed, the last SERVFAIL received, or `None` if there was none.
+    pub async fn query(&self, request: &Message) -> Option<Message> {
+        if self.strategy == UpstreamStrategy::Race {
+            return self.race(request).await;
+        }
+
+        let mut server_failure = None;
+        for upstream in self.order() {
+            match upstream.query(request).await {
+                Ok(response) if response.rcode() == RCode::SERVFAIL => {
+                    eprintln!("Upstream {} answered with SERVFAIL", upstream.address);
+                    server_failure = Some(response);
+                }
+                Ok(response) => return Some(response),
+                Err(e) => eprintln!("Upstream {} failed: {}", upstream.address, e),
+            }
+        }
+        server_failure
+    }
+
+    /// Sends the request to every upstream at once and returns the first valid response other
+    /// than SERVFAIL, or the last SERVFAIL if no upstream does better. The queries still
+    /// outstanding are cancelled once a response is returned.
+    async fn race(&self, request: &Message) -> Option<Message> {
+        let request = Arc::new(request.clone());
+        let mut queries = JoinSet::new();
+        for upstream in self.available() {
+            let upstream = upstream.clone();
+            let request = request.clone();
+            queries.spawn(async move {
+                let result = upstream.query(&request).await;
+                (upstream, result)
+            });
+        }
+
+        let mut server_failure = None;
+        while let Some(joined) = queries.join_next().await {
+            match joined {
+                Ok((upstream, Ok(response))) if response.rcode() == RCode::SERVFAIL => {
+                    eprintln!("Upstream {} answered with SERVFAIL", upstream.address);
+                    server_failure = Some(response);
+                }
+                Ok((_, Ok(response))) => return Some(response),
+                Ok((upstream, Err(e))) => eprintln!("Upstream {} failed: {}", upstream.address, e),
+                Err(e) => eprintln!("Upstream query task failed: {}", e),
+            }
+        }
+        server_failure
+    }
+
+    /// Gets the upstreams that are up, or every upstream as a last resort if none are.
+    fn available(&self) -> Vec<&Arc<Upstream>> {
+        let up: Vec<_> = self.upstreams.iter().filter(|u| u.is_up()).collect();
+        if up.is_empty() {
+            self.upstreams.iter().collect()
+        } else {
+            up
+        }
+    }
+
+    /// Orders the available upstreams in which they should be tried for the next request.
+    fn order(&self) -> Vec<&Upstream> {
+        let mut order: Vec<&Upstream> = self.available().into_iter().map(Arc::as_ref).collect();
+        match self.strategy {
+            UpstreamStrategy::Failover | UpstreamStrategy::Race => {}
+            UpstreamStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = order.len();
+                if len > 0 {
+                    order.rotate_left(start % len);
+                }
+            }
+            UpstreamStrategy::Weighted => {
+                // Weighted random order without replacement (Efraimidis-Spirakis): each upstream
+                // draws u^(1/weight) and the highest draws go first.
+                let mut keyed: Vec<(f64, &Upstream)> = order
+                    .into_iter()
+                    .map(|upstream| {
+                        let weight = upstream.weight.max(1) as f64;
+                        (rand::random::<f64>().powf(1.0 / weight), upstream)
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                order = keyed.into_iter().map(|(_, upstream)| upstream).collect();
+            }
+            UpstreamStrategy::Fastest => {
+                // Upstreams that have not answered yet have an RTT of 0, so they are measured first.
+                order.sort_by_key(|upstream| upstream.rtt.load(Ordering::Relaxed));
+            }
+        }
+        order
     }
 }
 
 struct Upstream {
-    address: String,
-}
\ No newline at end of file
+    address: SocketAddr,
+    transport: Transport,
+    timeout: Duration,
+    weight: u32,
+    /// Exponentially weighted moving average of the response time in microseconds, 0 until measured.
+    rtt: AtomicU64,
+    /// The number of queries that failed or timed out.
+    errors: AtomicU64,
+    policy: HealthPolicy,
+    health: Mutex<Health>,
+}
+
+/// How queries reach an upstream.
+#[allow(clippy::upper_case_acronyms)]
+enum Transport {
+    /// Plain DNS over UDP, retried over TCP when the response is truncated.
+    UDP,
+    /// Plain DNS over TCP, one connection per query.
+    TCP,
+    /// DNS over TLS, RFC 7858, over persistent pipelined connections.
+    TLS(tls::TlsTransport),
+    /// DNS over HTTPS, RFC 8484, over a persistent HTTP/2 connection.
+    HTTPS(https::HttpsTransport),
+    /// DNS over QUIC, RFC 9250, over a persistent connection with a stream per query.
+    QUIC(quic::QuicTransport),
+}
+
+/// When upstreams are marked down and how long they are left alone afterwards.
+#[derive(Clone, Copy)]
+struct HealthPolicy {
+    failure_threshold: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    /// Set while the upstream is down.
+    down: Option<Backoff>,
+}
+
+struct Backoff {
+    /// When the upstream should next be probed or given a trial query.
+    retry_at: Instant,
+    /// The delay that led to `retry_at`, doubled after every failure while down.
+    delay: Duration,
+}
+
+impl Upstream {
+    fn new(settings: &UpstreamSettings, policy: HealthPolicy) -> io::Result<Self> {
+        // Upstreams must be given by IP address, as resolving a hostname would go through ourselves.
+        let ip: IpAddr = settings.address.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid upstream address: {}", settings.address),
+            )
+        })?;
+        let address = SocketAddr::new(ip, settings.port);
+
+        let transport = match settings.protocol {
+            UpstreamProtocol::UDP => Transport::UDP,
+            UpstreamProtocol::TCP => Transport::TCP,
+            UpstreamProtocol::TLS => Transport::TLS(tls::TlsTransport::new(address, settings)?),
+            UpstreamProtocol::HTTPS => {
+                Transport::HTTPS(https::HttpsTransport::new(address, settings)?)
+            }
+            UpstreamProtocol::QUIC => Transport::QUIC(quic::QuicTransport::new(address, settings)?),
+        };
+
+        Ok(Self {
+            address,
+            transport,
+            timeout: Duration::from_millis(settings.timeout),
+            weight: settings.weight,
+            rtt: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            policy,
+            health: Mutex::new(Health::default()),
+        })
+    }
+
+    /// Sends a client's request to this upstream and records how long it took or that it failed.
+    /// A SERVFAIL answer is returned, but counts as a failure just like no answer at all.
+    async fn query(&self, request: &Message) -> io::Result<Message> {
+        let started = Instant::now();
+        let result = self.exchange(request).await;
+        let success = result
+            .as_ref()
+            .is_ok_and(|response| response.rcode() != RCode::SERVFAIL);
+        self.record_result(started, success);
+        result
+    }
+
+    /// Sends a health probe to this upstream. Unlike client queries, a REFUSED answer counts as
+    /// a failure too, since the probe name is expected to resolve.
+    async fn probe(&self, probe: &Message) -> io::Result<()> {
+        let started = Instant::now();
+        let result = self
+            .exchange(probe)
+            .await
+            .and_then(|response| match response.rcode() {
+                RCode::NOERROR | RCode::NXDOMAIN => Ok(()),
+                rcode => Err(io::Error::other(format!("Probe answered with {:?}", rcode))),
+            });
+        self.record_result(started, result.is_ok());
+        result
+    }
+
+    /// Updates the response time and health of this upstream after a query or probe.
+    fn record_result(&self, started: Instant, success: bool) {
+        // Failures count as taking the full timeout so that the fastest strategy avoids them.
+        let elapsed = if success {
+            started.elapsed()
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            self.timeout
+        };
+        self.record_rtt(elapsed);
+
+        let health = &mut *self.health.lock().unwrap();
+        if success {
+            health.consecutive_failures = 0;
+            if health.down.take().is_some() {
+                println!("Upstream {} is up", self.address);
+            }
+            return;
+        }
+
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        match health.down.as_mut() {
+            Some(backoff) => {
+                backoff.delay = (backoff.delay * 2).min(self.policy.max_backoff);
+                backoff.retry_at = Instant::now() + backoff.delay;
+            }
+            None if health.consecutive_failures >= self.policy.failure_threshold => {
+                println!(
+                    "Upstream {} is down after {} consecutive failures ({} failures in total)",
+                    self.address,
+                    health.consecutive_failures,
+                    self.errors.load(Ordering::Relaxed)
+                );
+                health.down = Some(Backoff {
+                    retry_at: Instant::now() + self.policy.initial_backoff,
+                    delay: self.policy.initial_backoff,
+                });
+            }
+            None => {}
+        }
+    }
+
+    /// Gets whether this upstream should be used for client queries.
+    ///
+    /// Once the backoff of a down upstream has passed, one query is let through as a trial and
+    /// the backoff starts over, so that the upstream recovers even when probes are disabled.
+    fn is_up(&self) -> bool {
+        let health = &mut *self.health.lock().unwrap();
+        match health.down.as_mut() {
+            None => true,
+            Some(backoff) if backoff.retry_at <= Instant::now() => {
+                backoff.retry_at = Instant::now() + backoff.delay;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Gets how long to wait before probing this upstream again.
+    fn next_probe_delay(&self, interval: Duration) -> Duration {
+        match &self.health.lock().unwrap().down {
+            Some(backoff) => backoff.retry_at.saturating_duration_since(Instant::now()),
+            None => interval,
+        }
+    }
+
+    /// Folds a response time into the moving average, with the usual smoothing factor of 1/8.
+    fn record_rtt(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let _ = self
+            .rtt
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rtt| {
+                Some(if rtt == 0 {
+                    sample
+                } else {
+                    (rtt * 7 + sample) / 8
+                })
+            });
+    }
+
+    /// Sends a client's request to this upstream under a fresh, random transaction ID.
+    ///
+    /// UDP responses with the TC bit set are retried over TCP, RFC 7766 5.
+    async fn exchange(&self, request: &Message) -> io::Result<Message> {
+        let deadline = Instant::now() + self.timeout;
+        // Encrypted transports pick the transaction ID themselves, as their connections are shared.
+        match &self.transport {
+            Transport::TLS(tls) => return within(deadline, tls.query(request)).await,
+            Transport::HTTPS(https) => return within(deadline, https.query(request)).await,
+            Transport::QUIC(quic) => return within(deadline, quic.query(request)).await,
+            Transport::UDP | Transport::TCP => {}
+        }
+
+        let query = Message::new_query(request, rand::random());
+        if let Transport::UDP = self.transport {
+            let response = self.query_udp(&query, deadline).await?;
+            if !response.is_truncated() {
+                return Ok(response);
+            }
+        }
+        self.query_tcp(&query, deadline).await
+    }
+
+    async fn query_udp(&self, query: &Message, deadline: Instant) -> io::Result<Message> {
+        // Binding to port 0 lets the OS pick a random source port for every query.
+        let local: IpAddr = match self.address {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        // A connected socket drops datagrams from any other source address.
+        socket.connect(self.address).await?;
+        socket.send(&query.serialize()).await?;
+
+        // Upstreams must not send more than the payload size our query advertised.
+        let mut buf = vec![0u8; query.max_udp_response_size()];
+        loop {
+            let size = within(deadline, socket.recv(&mut buf)).await?;
+            // Anything that does not answer our query is ignored, as it may be spoofed.
+            match Message::deserialize(&buf[..size]) {
+                Ok(response) if response.is_response_to(query) => return Ok(response),
+                Ok(_) => eprintln!("Ignoring mismatched response from {}", self.address),
+                Err(e) => eprintln!("Ignoring malformed response from {}: {}", self.address, e),
+            }
+        }
+    }
+
+    async fn query_tcp(&self, query: &Message, deadline: Instant) -> io::Result<Message> {
+        within(deadline, self.exchange_tcp(query)).await
+    }
+
+    async fn exchange_tcp(&self, query: &Message) -> io::Result<Message> {
+        let mut stream = TcpStream::connect(self.address).await?;
+        stream.write_all(&query.serialize_framed()).await?;
+
+        let response = read_frame(&mut stream).await?;
+        if !response.is_response_to(query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Response does not match query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+/// Reads one length-prefixed message from a stream.
+async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Message> {
+    let buf = read_raw_frame(stream).await?;
+    Message::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the bytes of one length-prefixed message from a stream without parsing them.
+async fn read_raw_frame(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Runs an exchange with an upstream, failing it if it is not done by the deadline.
+async fn within<T>(
+    deadline: Instant,
+    exchange: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout_at(deadline, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(timed_out()),
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Upstream did not respond in time")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::HttpMethod;
+
+    /// Creates the settings of a UDP upstream on the loopback address.
+    fn upstream(port: u16, weight: u32) -> UpstreamSettings {
+        UpstreamSettings {
+            address: "127.0.0.1".to_string(),
+            port,
+            protocol: UpstreamProtocol::UDP,
+            timeout: 1000,
+            weight,
+            tls_name: None,
+            ca_file: None,
+            spki_pins: Vec::new(),
+            connections: 1,
+            url: None,
+            method: HttpMethod::POST,
+        }
+    }
+
+    #[test]
+    fn zero_health_check_delays_are_rejected() {
+        let upstreams = [upstream(53, 1)];
+        for health_check in [
+            HealthCheckSettings {
+                interval: 0,
+                ..Default::default()
+            },
+            HealthCheckSettings {
+                initial_backoff: 0,
+                ..Default::default()
+            },
+            HealthCheckSettings {
+                max_backoff: 0,
+                ..Default::default()
+            },
+        ] {
+            let result = Upstreams::new(&upstreams, UpstreamStrategy::Failover, &health_check);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(
+            Upstreams::new(&upstreams, UpstreamStrategy::Failover, &Default::default()).is_ok()
+        );
+    }
+
+    /// Creates upstreams on ports 1, 2, ... with the given weights.
+    fn upstreams(strategy: UpstreamStrategy, weights: &[u32]) -> Upstreams {
+        let settings: Vec<_> = (1..)
+            .zip(weights)
+            .map(|(port, &weight)| upstream(port, weight))
+            .collect();
+        Upstreams::new(&settings, strategy, &Default::default()).unwrap()
+    }
+
+    /// Gets the ports of the upstreams in the order they would be tried.
+    fn order(upstreams: &Upstreams) -> Vec<u16> {
+        upstreams
+            .order()
+            .iter()
+            .map(|upstream| upstream.address.port())
+            .collect()
+    }
+
+    #[test]
+    fn failover_keeps_the_configured_order() {
+        let upstreams = upstreams(UpstreamStrategy::Failover, &[1, 1, 1]);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+    }
+
+    #[test]
+    fn round_robin_starts_with_the_next_upstream_each_time() {
+        let upstreams = upstreams(UpstreamStrategy::RoundRobin, &[1, 1, 1]);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+        assert_eq!(order(&upstreams), [2, 3, 1]);
+        assert_eq!(order(&upstreams), [3, 1, 2]);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+    }
+
+    #[test]
+    fn fastest_starts_with_the_lowest_average_response_time() {
+        let upstreams = upstreams(UpstreamStrategy::Fastest, &[1, 1, 1]);
+        upstreams.upstreams[0].record_rtt(Duration::from_millis(30));
+        upstreams.upstreams[1].record_rtt(Duration::from_millis(10));
+        // Upstreams that have not answered yet are tried first, to measure them.
+        assert_eq!(order(&upstreams), [3, 2, 1]);
+
+        // One slow response only moves the average an eighth of the way.
+        upstreams.upstreams[1].record_rtt(Duration::from_millis(90));
+        assert_eq!(upstreams.upstreams[1].rtt.load(Ordering::Relaxed), 20_000);
+        upstreams.upstreams[2].record_rtt(Duration::from_millis(25));
+        assert_eq!(order(&upstreams), [2, 3, 1]);
+    }
+
+    #[test]
+    fn weighted_starts_with_upstreams_in_proportion_to_their_weight() {
+        let upstreams = upstreams(UpstreamStrategy::Weighted, &[1, 9]);
+        let mut first = [0, 0];
+        for _ in 0..10_000 {
+            let order = order(&upstreams);
+            assert_eq!(order.len(), 2);
+            first[order[0] as usize - 1] += 1;
+        }
+        // The heavier upstream comes first 90% of the time.
+        assert!((8_500..9_500).contains(&first[1]), "{:?}", first);
+    }
+
+    #[test]
+    fn upstreams_that_are_down_are_only_tried_when_all_are() {
+        let upstreams = upstreams(UpstreamStrategy::Failover, &[1, 1]);
+        for _ in 0..3 {
+            upstreams.upstreams[0].record_result(Instant::now(), false);
+        }
+        assert_eq!(order(&upstreams), [2]);
+
+        for _ in 0..3 {
+            upstreams.upstreams[1].record_result(Instant::now(), false);
+        }
+        assert_eq!(order(&upstreams), [1, 2]);
+    }
+
+    /// Gets the backoff delay of an upstream, or `None` while it is up.
+    fn backoff(upstream: &Upstream) -> Option<Duration> {
+        let health = upstream.health.lock().unwrap();
+        health.down.as_ref().map(|backoff| backoff.delay)
+    }
+
+    #[test]
+    fn upstreams_are_marked_down_after_consecutive_failures() {
+        let health_check = HealthCheckSettings {
+            failure_threshold: 3,
+            ..Default::default()
+        };
+        let upstreams =
+            Upstreams::new(&[upstream(1, 1)], UpstreamStrategy::Failover, &health_check).unwrap();
+        let upstream = &upstreams.upstreams[0];
+
+        upstream.record_result(Instant::now(), false);
+        upstream.record_result(Instant::now(), false);
+        // A success in between starts the count over.
+        upstream.record_result(Instant::now(), true);
+        upstream.record_result(Instant::now(), false);
+        upstream.record_result(Instant::now(), false);
+        assert!(upstream.is_up());
+
+        upstream.record_result(Instant::now(), false);
+        assert!(!upstream.is_up());
+        assert_eq!(upstream.errors.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_its_maximum() {
+        let health_check = HealthCheckSettings {
+            failure_threshold: 1,
+            initial_backoff: 5,
+            max_backoff: 15,
+            ..Default::default()
+        };
+        let upstreams =
+            Upstreams::new(&[upstream(1, 1)], UpstreamStrategy::Failover, &health_check).unwrap();
+        let upstream = &upstreams.upstreams[0];
+
+        let mut delays = Vec::new();
+        for _ in 0..4 {
+            upstream.record_result(Instant::now(), false);
+            delays.push(backoff(upstream).unwrap().as_secs());
+        }
+        assert_eq!(delays, [5, 10, 15, 15]);
+        let interval = Duration::from_secs(30);
+        assert!(upstream.next_probe_delay(interval) <= Duration::from_secs(15));
+
+        upstream.record_result(Instant::now(), true);
+        assert_eq!(backoff(upstream), None);
+        assert_eq!(upstream.next_probe_delay(interval), interval);
+    }
+
+    #[test]
+    fn down_upstreams_get_one_trial_query_once_their_backoff_has_passed() {
+        let health_check = HealthCheckSettings {
+            failure_threshold: 1,
+            ..Default::default()
+        };
+        let upstreams =
+            Upstreams::new(&[upstream(1, 1)], UpstreamStrategy::Failover, &health_check).unwrap();
+        let upstream = &upstreams.upstreams[0];
+        upstream.record_result(Instant::now(), false);
+        assert!(!upstream.is_up());
+
+        upstream
+            .health
+            .lock()
+            .unwrap()
+            .down
+            .as_mut()
+            .unwrap()
+            .retry_at = Instant::now();
+        assert!(upstream.is_up());
+        assert!(!upstream.is_up());
+
+        // The trial query answered, so the upstream is back for good.
+        upstream.record_result(Instant::now(), true);
+        assert!(upstream.is_up());
+        assert!(upstream.is_up());
+    }
+}
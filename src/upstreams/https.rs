@@ -0,0 +1,243 @@
+use super::slot::{ConnectionSlot, Reusable};
+use crate::dns::Message;
+use crate::settings::{HttpMethod, UpstreamSettings};
+use crate::tls_config::{self, ALPN_H2, DNS_MESSAGE};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// The URI template variable that GET requests carry the query in, RFC 8484 4.1.
+const TEMPLATE_VARIABLE: &str = "{?dns}";
+
+/// DNS over HTTPS to one upstream, RFC 8484, over a single multiplexed HTTP/2 connection.
+pub(super) struct HttpsTransport {
+    address: SocketAddr,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    /// The URL queries are sent to, with the template variable removed.
+    url: String,
+    method: HttpMethod,
+    /// The connection every query is multiplexed over.
+    connection: ConnectionSlot<SendRequest<Full<Bytes>>>,
+}
+
+impl HttpsTransport {
+    pub(super) fn new(address: SocketAddr, settings: &UpstreamSettings) -> io::Result<Self> {
+        let template = settings.url.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("HTTPS upstream {} has no url", settings.address),
+            )
+        })?;
+        let url = template.replace(TEMPLATE_VARIABLE, "");
+        let host = url
+            .parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.scheme_str() == Some("https"))
+            .and_then(|uri| {
+                uri.host()
+                    .map(|host| host.trim_matches(['[', ']']).to_string())
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid upstream url: {}", template),
+                )
+            })?;
+
+        let name = settings.tls_name.clone().unwrap_or(host);
+        let server_name = ServerName::try_from(name.clone()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid upstream TLS name: {}", name),
+            )
+        })?;
+        let config = tls_config::client_config(
+            settings.ca_file.as_deref(),
+            &settings.spki_pins,
+            &[ALPN_H2],
+        )?;
+
+        Ok(Self {
+            address,
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+            url,
+            method: settings.method,
+            connection: ConnectionSlot::new(),
+        })
+    }
+
+    /// Sends a client's request as its own HTTP/2 stream.
+    pub(super) async fn query(&self, request: &Message) -> io::Result<Message> {
+        self.connection
+            .query(|| self.connect(), |sender| self.send(sender, request))
+            .await
+    }
+
+    async fn connect(&self) -> io::Result<SendRequest<Full<Bytes>>> {
+        let stream = TcpStream::connect(self.address).await?;
+        stream.set_nodelay(true)?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        if stream.get_ref().1.alpn_protocol() != Some(ALPN_H2) {
+            return Err(io::Error::other("Upstream does not support HTTP/2"));
+        }
+
+        let (sender, driver) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .map_err(io::Error::other)?;
+        // The driver ends when either side closes the connection, which marks the sender closed.
+        tokio::spawn(driver);
+        Ok(sender)
+    }
+
+    async fn send(
+        &self,
+        mut sender: SendRequest<Full<Bytes>>,
+        request: &Message,
+    ) -> io::Result<Message> {
+        // A zero ID keeps identical queries identical for HTTP caches, RFC 8484 4.1.
+        let query = Message::new_query(request, 0);
+        let query_bytes = query.serialize();
+
+        let builder = match self.method {
+            HttpMethod::POST => Request::post(self.url.as_str()).header(CONTENT_TYPE, DNS_MESSAGE),
+            HttpMethod::GET => {
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                let encoded = URL_SAFE_NO_PAD.encode(&query_bytes);
+                Request::get(format!("{}{}dns={}", self.url, separator, encoded))
+            }
+        };
+        let body = match self.method {
+            HttpMethod::POST => Full::new(Bytes::from(query_bytes)),
+            HttpMethod::GET => Full::default(),
+        };
+        let http_request = builder
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(body)
+            .map_err(io::Error::other)?;
+
+        let http_response = sender
+            .send_request(http_request)
+            .await
+            .map_err(io::Error::other)?;
+        if http_response.status() != StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "Upstream answered with HTTP status {}",
+                http_response.status()
+            )));
+        }
+        if http_response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|v| v.as_bytes())
+            != Some(DNS_MESSAGE.as_bytes())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Upstream answered with an unexpected content type",
+            ));
+        }
+
+        let body = Limited::new(http_response.into_body(), u16::MAX as usize)
+            .collect()
+            .await
+            .map_err(io::Error::other)?
+            .to_bytes();
+        let response = Message::deserialize(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !response.is_response_to(&query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Upstream response does not match the query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+impl Reusable for SendRequest<Full<Bytes>> {
+    fn is_closed(&self) -> bool {
+        SendRequest::is_closed(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{RCode, RData, RecordType};
+    use crate::listeners::testing::{self, CERTIFICATE_FILE, KEY_FILE};
+    use crate::settings::UpstreamProtocol;
+    use crate::tls_config::DNS_JSON;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    #[tokio::test]
+    async fn queries_are_sent_with_get_and_post() {
+        let (addresses, mut queue) = testing::listen().await;
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+
+        for method in [HttpMethod::GET, HttpMethod::POST] {
+            let mut settings = testing::upstream(UpstreamProtocol::HTTPS, addresses.https);
+            settings.method = method;
+            let transport = HttpsTransport::new(addresses.https, &settings).unwrap();
+            let server = async {
+                let request = queue.recv().await.unwrap();
+                assert_eq!(request.message.id(), 0);
+                testing::answer(request).await;
+            };
+            let (response, ()) = tokio::join!(transport.query(&query), server);
+            let response = response.unwrap();
+            assert_eq!(response.answers()[0].rdata(), &RData::A(testing::ADDRESS));
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_of_another_content_type_are_rejected() {
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+        let response = Message::new_query(&query, 0);
+        let response = Message::new_response(&response, RCode::NOERROR, Vec::new(), Vec::new());
+        let body = Bytes::from(response.serialize());
+
+        let config = tls_config::server_config(CERTIFICATE_FILE, KEY_FILE, &[ALPN_H2]).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            // A well-formed response, but labelled with the media type of the JSON format.
+            let service = service_fn(move |_| {
+                let response = Response::builder()
+                    .header(CONTENT_TYPE, DNS_JSON)
+                    .body(Full::new(body.clone()));
+                async { response }
+            });
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+        });
+
+        let settings = testing::upstream(UpstreamProtocol::HTTPS, address);
+        let transport = HttpsTransport::new(address, &settings).unwrap();
+        let error = transport.query(&query).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
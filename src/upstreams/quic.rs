@@ -0,0 +1,229 @@
+use super::slot::{ConnectionSlot, Reusable};
+use crate::dns::Message;
+use crate::settings::UpstreamSettings;
+use crate::tls_config::{self, ALPN_DOQ};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint, ReadToEndError, WriteError};
+use rustls::pki_types::ServerName;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// DNS over QUIC to one upstream, RFC 9250, with one stream per query over a shared connection.
+pub(super) struct QuicTransport {
+    address: SocketAddr,
+    server_name: String,
+    endpoint: Endpoint,
+    config: ClientConfig,
+    /// The connection every query opens a stream on.
+    connection: ConnectionSlot<QuicConnection>,
+}
+
+/// A connection to the upstream that may still be sending 0-RTT data.
+#[derive(Clone)]
+struct QuicConnection {
+    connection: Connection,
+    /// Becomes `true` once the handshake has completed.
+    handshake: watch::Receiver<bool>,
+}
+
+impl QuicTransport {
+    pub(super) fn new(address: SocketAddr, settings: &UpstreamSettings) -> io::Result<Self> {
+        let mut crypto = tls_config::client_config(
+            settings.ca_file.as_deref(),
+            &settings.spki_pins,
+            &[ALPN_DOQ],
+        )?;
+        // Session tickets from earlier connections let new ones carry queries in 0-RTT.
+        crypto.enable_early_data = true;
+        let crypto = QuicClientConfig::try_from(crypto).map_err(io::Error::other)?;
+
+        let server_name = settings
+            .tls_name
+            .clone()
+            .unwrap_or_else(|| settings.address.clone());
+        if ServerName::try_from(server_name.as_str()).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid upstream TLS name: {}", server_name),
+            ));
+        }
+
+        let local: IpAddr = match address {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        Ok(Self {
+            address,
+            server_name,
+            endpoint: Endpoint::client(SocketAddr::new(local, 0))?,
+            config: ClientConfig::new(Arc::new(crypto)),
+            connection: ConnectionSlot::new(),
+        })
+    }
+
+    /// Sends a client's request on a new stream of the shared connection.
+    ///
+    /// A query that the server refused as 0-RTT data is sent again once the handshake is done.
+    pub(super) async fn query(&self, request: &Message) -> io::Result<Message> {
+        // Only standard queries can be replayed without harm, RFC 9250 4.5.
+        let early_data = request.opcode() == 0;
+        self.connection
+            .query(
+                || self.connect(),
+                |connection| async move {
+                    // A connection opened for an earlier query may still be in 0-RTT.
+                    if !early_data {
+                        connection.handshake().await;
+                    }
+                    let connection = &connection.connection;
+                    match Self::exchange(connection, request).await {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                            Self::exchange(connection, request).await
+                        }
+                        result => result,
+                    }
+                },
+            )
+            .await
+    }
+
+    async fn connect(&self) -> io::Result<QuicConnection> {
+        let connecting = self
+            .endpoint
+            .connect_with(self.config.clone(), self.address, &self.server_name)
+            .map_err(io::Error::other)?;
+        // 0-RTT is only possible with a session ticket from an earlier connection.
+        let (connection, handshake) = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                let (done, handshake) = watch::channel(false);
+                tokio::spawn(async move {
+                    accepted.await;
+                    let _ = done.send(true);
+                });
+                (connection, handshake)
+            }
+            Err(connecting) => (connecting.await?, watch::channel(true).1),
+        };
+        Ok(QuicConnection {
+            connection,
+            handshake,
+        })
+    }
+
+    async fn exchange(connection: &Connection, request: &Message) -> io::Result<Message> {
+        // The ID must be 0, as the stream already identifies the query, RFC 9250 4.2.1.
+        let query = Message::new_query(request, 0);
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&query.serialize_framed())
+            .await
+            .map_err(|e| match e {
+                WriteError::ZeroRttRejected => zero_rtt_rejected(),
+                e => e.into(),
+            })?;
+        send.finish()?;
+
+        let frame = recv
+            .read_to_end(u16::MAX as usize + 2)
+            .await
+            .map_err(|e| match e {
+                ReadToEndError::Read(quinn::ReadError::ZeroRttRejected) => zero_rtt_rejected(),
+                e => io::Error::other(e),
+            })?;
+        let response = match frame.split_first_chunk::<2>() {
+            Some((length, message)) if u16::from_be_bytes(*length) as usize == message.len() => {
+                Message::deserialize(message)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Upstream response is not a single length-prefixed message",
+                ))
+            }
+        };
+        if !response.is_response_to(&query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Upstream response does not match the query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+impl QuicConnection {
+    /// Waits until the handshake has completed, after which nothing is sent as 0-RTT data.
+    async fn handshake(&self) {
+        // The value only ever becomes `true`, so the sender going away is not an error.
+        let _ = self.handshake.clone().wait_for(|done| *done).await;
+    }
+}
+
+impl Reusable for QuicConnection {
+    fn is_closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+}
+
+/// The error for a query sent as 0-RTT data that the server refused, which is safe to resend
+/// now that the handshake has completed.
+fn zero_rtt_rejected() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "0-RTT data was rejected")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{RData, RecordType};
+    use crate::listeners::testing;
+    use crate::settings::UpstreamProtocol;
+
+    #[tokio::test]
+    async fn queries_are_sent_with_a_zero_id() {
+        let (addresses, mut queue) = testing::listen().await;
+        let settings = testing::upstream(UpstreamProtocol::QUIC, addresses.quic);
+        let transport = QuicTransport::new(addresses.quic, &settings).unwrap();
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+
+        let server = async {
+            let request = queue.recv().await.unwrap();
+            assert_eq!(request.message.id(), 0);
+            testing::answer(request).await;
+        };
+        let (response, ()) = tokio::join!(transport.query(&query), server);
+        let response = response.unwrap();
+        assert_eq!(response.answers()[0].rdata(), &RData::A(testing::ADDRESS));
+    }
+
+    #[tokio::test]
+    async fn queries_rejected_as_0rtt_data_are_sent_again() {
+        let (first, queue) = testing::listen().await;
+        testing::answer_all(queue);
+        let (second, mut queue) = testing::listen().await;
+        let settings = testing::upstream(UpstreamProtocol::QUIC, first.quic);
+        let transport = QuicTransport::new(first.quic, &settings).unwrap();
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+        transport.query(&query).await.unwrap();
+
+        // Both listeners present the same certificate, but only the first knows the session
+        // tickets it issued, so the second rejects the 0-RTT data they are used for.
+        let mut resumed = QuicTransport::new(second.quic, &settings).unwrap();
+        resumed.config = transport.config.clone();
+        let connecting = resumed
+            .endpoint
+            .connect_with(resumed.config.clone(), second.quic, "localhost")
+            .unwrap();
+        assert!(connecting.into_0rtt().is_ok());
+
+        let server = tokio::spawn(async move {
+            testing::answer(queue.recv().await.unwrap()).await;
+            queue
+        });
+        let response = resumed.query(&query).await.unwrap();
+        assert_eq!(response.answers()[0].rdata(), &RData::A(testing::ADDRESS));
+        // The rejected 0-RTT data never reached the listener, so it only saw the query once.
+        assert!(server.await.unwrap().try_recv().is_err());
+    }
+}
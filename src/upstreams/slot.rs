@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::io;
+use tokio::sync::Mutex;
+
+/// A connection that carries any number of queries until either side closes it.
+pub(super) trait Reusable: Clone {
+    fn is_closed(&self) -> bool;
+}
+
+/// A persistent connection to an upstream, opened on first use and replaced once it closes.
+pub(super) struct ConnectionSlot<C> {
+    connection: Mutex<Option<C>>,
+}
+
+impl<C: Reusable> ConnectionSlot<C> {
+    pub(super) fn new() -> Self {
+        Self {
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Sends a query over the connection, opening one first if there is none.
+    ///
+    /// A query that fails because the server closed an idle connection under it is retried
+    /// once on a new connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `connect` - Opens a new connection.
+    ///
+    /// * `exchange` - Sends the query over a connection and waits for the response.
+    pub(super) async fn query<T, Connect, Exchange>(
+        &self,
+        connect: impl Fn() -> Connect,
+        exchange: impl Fn(C) -> Exchange,
+    ) -> io::Result<T>
+    where
+        Connect: Future<Output = io::Result<C>>,
+        Exchange: Future<Output = io::Result<T>>,
+    {
+        let (connection, reused) = self.connection(&connect).await?;
+        match exchange(connection.clone()).await {
+            Err(_) if reused && connection.is_closed() => {
+                let (connection, _) = self.connection(&connect).await?;
+                exchange(connection).await
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the open connection, connecting first if there is none.
+    /// The flag is `true` if the connection had been used before.
+    async fn connection<Connect>(&self, connect: impl Fn() -> Connect) -> io::Result<(C, bool)>
+    where
+        Connect: Future<Output = io::Result<C>>,
+    {
+        let mut slot = self.connection.lock().await;
+        if let Some(connection) = slot.as_ref().filter(|c| !c.is_closed()) {
+            return Ok((connection.clone(), true));
+        }
+
+        let connection = connect().await?;
+        *slot = Some(connection.clone());
+        Ok((connection, false))
+    }
+}
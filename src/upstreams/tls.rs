@@ -0,0 +1,264 @@
+use super::read_raw_frame;
+use super::slot::{ConnectionSlot, Reusable};
+use crate::dns::Message;
+use crate::settings::UpstreamSettings;
+use crate::tls_config::{self, ALPN_DOT};
+use rustls::pki_types::ServerName;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// A pool of persistent DNS over TLS connections to one upstream, RFC 7858.
+pub(super) struct TlsTransport {
+    address: SocketAddr,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    /// The pooled connections, used in turn.
+    slots: Vec<ConnectionSlot<Arc<Connection>>>,
+    /// The slot the next query is sent on.
+    next: AtomicUsize,
+}
+
+impl TlsTransport {
+    pub(super) fn new(address: SocketAddr, settings: &UpstreamSettings) -> io::Result<Self> {
+        let name = settings
+            .tls_name
+            .clone()
+            .unwrap_or_else(|| settings.address.clone());
+        let server_name = ServerName::try_from(name.clone()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid upstream TLS name: {}", name),
+            )
+        })?;
+        let config = tls_config::client_config(
+            settings.ca_file.as_deref(),
+            &settings.spki_pins,
+            &[ALPN_DOT],
+        )?;
+
+        Ok(Self {
+            address,
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+            slots: (0..settings.connections.max(1))
+                .map(|_| ConnectionSlot::new())
+                .collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends a client's request over one of the pooled connections.
+    pub(super) async fn query(&self, request: &Message) -> io::Result<Message> {
+        let slot = &self.slots[self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len()];
+        slot.query(
+            || self.connect(),
+            |connection| async move { connection.query(request).await },
+        )
+        .await
+    }
+
+    async fn connect(&self) -> io::Result<Arc<Connection>> {
+        let stream = TcpStream::connect(self.address).await?;
+        stream.set_nodelay(true)?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let (reader, writer) = tokio::io::split(stream);
+
+        let connection = Arc::new(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(Some(HashMap::new())),
+        });
+        tokio::spawn(connection.clone().read_responses(reader));
+        Ok(connection)
+    }
+}
+
+/// A TLS connection with any number of queries in flight, told apart by transaction ID.
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    /// Where to deliver the response to each query in flight, or `None` once the connection is closed.
+    pending: Mutex<Option<HashMap<u16, oneshot::Sender<Message>>>>,
+}
+
+impl Connection {
+    async fn query(&self, request: &Message) -> io::Result<Message> {
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let pending = pending.as_mut().ok_or_else(closed)?;
+            loop {
+                let id = rand::random();
+                if let Entry::Vacant(entry) = pending.entry(id) {
+                    entry.insert(sender);
+                    break id;
+                }
+            }
+        };
+        // Forget the query if it fails or the caller gives up waiting.
+        let _pending = PendingQuery {
+            connection: self,
+            id,
+        };
+
+        let query = Message::new_query(request, id);
+        {
+            let mut writer = self.writer.lock().await;
+            // Close the connection if the write fails or the caller gives up during it.
+            let mut write = UnfinishedWrite {
+                connection: self,
+                finished: false,
+            };
+            writer.write_all(&query.serialize_framed()).await?;
+            writer.flush().await?;
+            write.finished = true;
+        }
+
+        let response = receiver.await.map_err(|_| closed())?;
+        if !response.is_response_to(&query) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Upstream response does not match the query",
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Hands each response to the query waiting for it until the connection is closed.
+    async fn read_responses(self: Arc<Self>, mut reader: ReadHalf<TlsStream<TcpStream>>) {
+        // Any read error, including a TLS alert or a failed record, ends the connection.
+        while let Ok(frame) = read_raw_frame(&mut reader).await {
+            // The whole frame was read, so the stream is still in sync if it does not parse.
+            let Ok(response) = Message::deserialize(&frame) else {
+                continue;
+            };
+            let sender = match self.pending.lock().unwrap().as_mut() {
+                Some(pending) => pending.remove(&response.id()),
+                None => break,
+            };
+            if let Some(sender) = sender {
+                let _ = sender.send(response);
+            }
+        }
+        self.close();
+    }
+
+    /// Marks the connection as closed and fails every query still waiting on it.
+    fn close(&self) {
+        self.pending.lock().unwrap().take();
+    }
+}
+
+impl Reusable for Arc<Connection> {
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+}
+
+/// Removes a query's entry from its connection when dropped.
+struct PendingQuery<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.connection.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// Closes its connection when dropped before the write it guards has finished, as a partly
+/// written frame leaves the stream out of sync for every later query.
+struct UnfinishedWrite<'a> {
+    connection: &'a Connection,
+    finished: bool,
+}
+
+impl Drop for UnfinishedWrite<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.connection.close();
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "TLS connection closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{RData, RecordType};
+    use crate::listeners::testing::{self, SPKI_PIN};
+    use crate::requests::ConnectionInfo;
+    use crate::settings::UpstreamProtocol;
+
+    #[tokio::test]
+    async fn only_servers_with_a_pinned_key_are_accepted() {
+        let (addresses, queue) = testing::listen().await;
+        testing::answer_all(queue);
+        let query = Message::new_simple_query("example.com", RecordType::A, 0x1234).unwrap();
+
+        let mut settings = testing::upstream(UpstreamProtocol::TLS, addresses.tls);
+        settings.spki_pins = vec![SPKI_PIN.to_string()];
+        let transport = TlsTransport::new(addresses.tls, &settings).unwrap();
+        let response = transport.query(&query).await.unwrap();
+        assert_eq!(response.answers()[0].rdata(), &RData::A(testing::ADDRESS));
+
+        settings.spki_pins = vec!["AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string()];
+        let transport = TlsTransport::new(addresses.tls, &settings).unwrap();
+        let error = transport.query(&query).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_are_matched_to_their_queries_by_id() {
+        let (addresses, mut queue) = testing::listen().await;
+        let settings = testing::upstream(UpstreamProtocol::TLS, addresses.tls);
+        let transport = TlsTransport::new(addresses.tls, &settings).unwrap();
+        let names = ["a.example", "b.example", "c.example"];
+        let queries = names.map(|name| Message::new_simple_query(name, RecordType::A, 0).unwrap());
+
+        // The queries all arrive on the one connection before any is answered, and are
+        // answered in reverse order.
+        let server = async {
+            let mut requests = Vec::new();
+            for _ in &names {
+                requests.push(queue.recv().await.unwrap());
+            }
+            let writers: Vec<_> = requests
+                .iter()
+                .map(|request| match &request.connection_info {
+                    ConnectionInfo::TCP { writer, .. } => writer.clone(),
+                    _ => unreachable!("TLS requests are read from a stream"),
+                })
+                .collect();
+            assert!(writers.iter().all(|w| Arc::ptr_eq(w, &writers[0])));
+            for request in requests.into_iter().rev() {
+                testing::answer(request).await;
+            }
+        };
+        let (a, b, c, ()) = tokio::join!(
+            transport.query(&queries[0]),
+            transport.query(&queries[1]),
+            transport.query(&queries[2]),
+            server
+        );
+        for (response, name) in [a, b, c].into_iter().zip(names) {
+            assert_eq!(response.unwrap().qname_to_string(), name);
+        }
+    }
+}